@@ -1,24 +1,71 @@
 use atty::Stream;
 use std::env;
+use std::fmt::Display;
+use std::fs;
 use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
 
-use rustyline::{error::ReadlineError, Editor};
-use sillyfmt::silly_format_iter;
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{error::ReadlineError, Editor, Helper};
+use sillyfmt::{brackets_closed, do_diff, do_format, silly_format_iter, Options};
 use sillyfmt_tree_sitter::parse;
 
+/// Shown by the interactive mode's `:help` command.
+const HELP: &str = "\
+Containers are formatted as soon as all their brackets are closed, and anything else after an
+empty line. Commands:
+  :again            format the previous input again, e.g. after changing an option
+  :reset            go back to the options sillyfmt was started with
+  :<flag> [value]   set an option the same way as --<flag> would, e.g. :width 100 or :align
+  :help             show this message
+  :quit             exit (as does Ctrl-D)";
+
 fn main() -> io::Result<()> {
     let mut format_on_newline = false;
     let mut print_debug = false;
-    for arg in env::args() {
+    let mut diff = None;
+    let mut explicit_embedded_payloads = false;
+    let mut options = Options::default();
+    let mut args = env::args();
+    while let Some(arg) = args.next() {
+        if arg == "diff" {
+            let old: String = parse_arg(&arg, args.next())?;
+            let new: String = parse_arg(&arg, args.next())?;
+            diff = Some((old, new));
+        }
         if arg == "--newline" {
             format_on_newline = true;
         }
         if arg == "--debug" {
             print_debug = true;
         }
+        if arg == "--embedded-payloads" || arg == "--no-embedded-payloads" {
+            explicit_embedded_payloads = true;
+        }
+        if arg.starts_with("--") {
+            set_option(&mut options, &arg, &mut args)?;
+        }
+    }
+    // Streamed lines are usually log lines, with their payloads surrounded by prose.
+    if !explicit_embedded_payloads {
+        options.embedded_payloads = format_on_newline;
+    }
+    if let Some((old, new)) = diff {
+        return do_diff(
+            io::stdout(),
+            &options,
+            fs::read_to_string(old)?,
+            fs::read_to_string(new)?,
+            atty::is(Stream::Stdout),
+            parse,
+        );
     }
-    if atty::is(Stream::Stdin) && !format_on_newline {
-        println!("Hit enter twice to format, or re-run with --newline");
+    if atty::is(Stream::Stdin) {
+        return repl(options, format_on_newline, print_debug);
     }
     let rl = Editor::<()>::new();
     struct EditorIter {
@@ -34,7 +81,7 @@ fn main() -> io::Result<()> {
                 Err(ReadlineError::Io(e)) => Some(Err(e)),
                 Err(e) => {
                     eprintln!("Unexpected err {:?}", e);
-                    Some(Err(io::Error::new(io::ErrorKind::Other, "unknown error")))
+                    Some(Err(io::Error::other("unknown error")))
                 }
             }
         }
@@ -42,12 +89,189 @@ fn main() -> io::Result<()> {
     silly_format_iter(
         &mut EditorIter { editor: rl },
         io::stdout(),
+        &options,
         format_on_newline,
-        if print_debug {
-            Some(std::io::stderr())
-        } else {
-            None
-        },
+        debug_writer(print_debug),
         parse,
     )
 }
+
+/// Applies a flag which sets one of the formatting options, given as `--flag` on the command
+/// line or `:flag` in the interactive mode, taking its value from `args` if it has one. Returns
+/// whether the flag was recognized.
+fn set_option(
+    options: &mut Options,
+    arg: &str,
+    args: &mut impl Iterator<Item = String>,
+) -> io::Result<bool> {
+    match arg.trim_start_matches(['-', ':']) {
+        "expand-escapes" => options.expand_escapes = true,
+        "align" => options.align_values = true,
+        "uppercase-keywords" => options.uppercase_keywords = true,
+        "trailing-commas" => options.trailing_commas = parse_arg(arg, args.next())?,
+        "drop-empty-elements" => options.drop_empty_elements = true,
+        "bracket-spacing" => options.bracket_spacing = parse_arg(arg, args.next())?,
+        "collapse-repeats" => options.collapse_repeats = true,
+        "max-elements" => options.max_elements = Some(parse_arg(arg, args.next())?),
+        "depth" => options.max_depth = Some(parse_arg(arg, args.next())?),
+        "width" => options.line_width = Some(parse_arg(arg, args.next())?),
+        "select" => options.select = Some(parse_arg(arg, args.next())?),
+        "to-json" => options.to_json = true,
+        "log-prefix" => options.log_prefix = Some(Default::default()),
+        "log-prefix-pattern" => options.log_prefix = Some(parse_arg(arg, args.next())?),
+        "embedded-payloads" => options.embedded_payloads = true,
+        "no-embedded-payloads" => options.embedded_payloads = false,
+        "unwrap-strings" => options.unwrap_encoded_strings = true,
+        "decode-blobs" => options.decode_blobs = true,
+        "full-blobs" => options.full_blobs = true,
+        "dialect" => options.dialect = parse_arg(arg, args.next())?,
+        "collapse-frames" => {
+            let patterns: String = parse_arg(arg, args.next())?;
+            options.collapse_frames = patterns.split(',').map(str::to_string).collect();
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn debug_writer(print_debug: bool) -> Option<io::Stderr> {
+    if print_debug {
+        Some(io::stderr())
+    } else {
+        None
+    }
+}
+
+/// Where the interactive mode keeps its history between sessions.
+fn history_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".sillyfmt_history"))
+}
+
+/// Formats input from a terminal as it's entered, until the user hits Ctrl-D or runs `:quit`.
+fn repl(mut options: Options, format_on_newline: bool, print_debug: bool) -> io::Result<()> {
+    println!("Type or paste something to format, or :help for commands");
+    let initial_options = options.clone();
+    let mut editor = Editor::new();
+    editor.set_helper(Some(InputHelper {
+        options: options.clone(),
+        format_on_newline,
+    }));
+    let history = history_path();
+    if let Some(path) = &history {
+        // There's nothing to load the first time around.
+        let _ = editor.load_history(path);
+    }
+
+    let mut previous: Option<String> = None;
+    loop {
+        let input = match editor.readline("sillyfmt> ") {
+            Ok(input) => input,
+            // Ctrl-C throws away whatever was being entered, rather than quitting.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(ReadlineError::Io(e)) => return Err(e),
+            Err(e) => return Err(io::Error::other(e.to_string())),
+        };
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(input);
+        if let Some(path) = &history {
+            if let Err(e) = editor.save_history(path) {
+                eprintln!("Couldn't save history to {}: {}", path.display(), e);
+            }
+        }
+
+        if !input.starts_with(':') {
+            let data = format!("{}\n", input);
+            do_format(
+                io::stdout(),
+                &options,
+                data.clone(),
+                debug_writer(print_debug),
+                parse,
+            )?;
+            previous = Some(data);
+            continue;
+        }
+        let mut words = input.split_whitespace().map(str::to_string);
+        let command = words.next().unwrap_or_default();
+        match command.as_str() {
+            ":quit" | ":q" => break,
+            ":help" => println!("{}", HELP),
+            ":again" => match &previous {
+                Some(data) => do_format(
+                    io::stdout(),
+                    &options,
+                    data.clone(),
+                    debug_writer(print_debug),
+                    parse,
+                )?,
+                None => eprintln!("There's nothing to format again yet"),
+            },
+            ":reset" => options = initial_options.clone(),
+            _ => match set_option(&mut options, &command, &mut words) {
+                Ok(true) => (),
+                Ok(false) => eprintln!("Unknown command {}, see :help", command),
+                Err(e) => eprintln!("{}", e),
+            },
+        }
+        // The dialect decides what counts as a comment when checking for closed brackets.
+        if let Some(helper) = editor.helper_mut() {
+            helper.options = options.clone();
+        }
+    }
+    Ok(())
+}
+
+/// Decides when the input entered so far is ready to be formatted: once it has closed all the
+/// brackets it opened, or after an empty line. Commands run as soon as they're entered.
+struct InputHelper {
+    options: Options,
+    format_on_newline: bool,
+}
+
+impl Validator for InputHelper {
+    fn validate(&self, ctx: &mut ValidationContext<'_>) -> rustyline::Result<ValidationResult> {
+        let input = ctx.input();
+        let complete = self.format_on_newline
+            || input.trim().is_empty()
+            || input.starts_with(':')
+            || input.ends_with('\n')
+            || brackets_closed(input, &self.options);
+        Ok(if complete {
+            ValidationResult::Valid(None)
+        } else {
+            ValidationResult::Incomplete
+        })
+    }
+}
+
+impl Completer for InputHelper {
+    type Candidate = String;
+}
+
+impl Hinter for InputHelper {}
+
+impl Highlighter for InputHelper {}
+
+impl Helper for InputHelper {}
+
+fn parse_arg<T: FromStr>(name: &str, value: Option<String>) -> io::Result<T>
+where
+    T::Err: Display,
+{
+    let value = value.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} requires a value", name),
+        )
+    })?;
+    value.parse().map_err(|e: T::Err| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid value for {}: {}", name, e),
+        )
+    })
+}
@@ -3,14 +3,21 @@ extern crate afl;
 
 use std::collections::HashMap;
 
-use sillyfmt::do_format;
+use sillyfmt::{do_format, Options};
 use sillyfmt_tree_sitter::parse;
 
 fn main() {
     fuzz!(|data: &[u8]| {
         if let Ok(s) = String::from_utf8(data.to_vec()) {
             let mut output_buffer = Vec::with_capacity(1024 * 1024);
-            do_format(&mut output_buffer, s.clone(), parse).unwrap();
+            do_format(
+                &mut output_buffer,
+                &Options::default(),
+                s.clone(),
+                None::<Vec<u8>>,
+                parse,
+            )
+            .unwrap();
 
             let required: HashMap<char, usize> =
                 s.chars()
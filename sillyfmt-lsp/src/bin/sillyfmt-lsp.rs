@@ -0,0 +1,12 @@
+use std::io;
+use std::process;
+
+use sillyfmt::Options;
+use sillyfmt_lsp::serve;
+
+fn main() -> io::Result<()> {
+    let stdin = io::stdin();
+    let clean = serve(stdin.lock(), io::stdout(), Options::default())?;
+    // The client is supposed to ask the server to shut down before telling it to exit.
+    process::exit(if clean { 0 } else { 1 });
+}
@@ -0,0 +1,391 @@
+//! A language server which exposes `sillyfmt` to editors: whole-document and range formatting,
+//! along with folding ranges for each container which spans several lines.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value};
+use sillyfmt::{container_ranges, do_format, format_range, Options};
+use sillyfmt_tree_sitter::parse;
+
+/// JSON-RPC's error code for messages which aren't valid JSON.
+const PARSE_ERROR: i64 = -32700;
+/// JSON-RPC's error code for requests the server doesn't support.
+const METHOD_NOT_FOUND: i64 = -32601;
+/// JSON-RPC's error code for requests with missing or malformed parameters.
+const INVALID_PARAMS: i64 = -32602;
+/// JSON-RPC's error code for requests which failed.
+const INTERNAL_ERROR: i64 = -32603;
+
+/// Serves requests from `reader` until the client sends `exit` or hangs up. Returns whether the
+/// client asked the server to shut down first, as it's supposed to.
+pub fn serve(
+    mut reader: impl BufRead,
+    mut writer: impl Write,
+    options: Options,
+) -> io::Result<bool> {
+    let mut server = Server {
+        options,
+        documents: HashMap::new(),
+        shut_down: false,
+    };
+    while let Some(message) = read_message(&mut reader)? {
+        let message = match message {
+            Ok(message) => message,
+            // There's no telling which request this was, so the error can't carry its id.
+            Err(e) => {
+                let response = json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": { "code": PARSE_ERROR, "message": e.to_string() },
+                });
+                write_message(&mut writer, &response)?;
+                continue;
+            }
+        };
+        let method = message["method"].as_str().unwrap_or_default();
+        if method == "exit" {
+            break;
+        }
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => {
+                server.notify(method, &message["params"]);
+                continue;
+            }
+        };
+        let response = match server.request(method, &message["params"]) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, error)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": error },
+            }),
+        };
+        write_message(&mut writer, &response)?;
+    }
+    Ok(server.shut_down)
+}
+
+/// Reads the next message, or returns `None` at the end of the input. A body which isn't valid
+/// JSON is returned as an error alongside, since the messages after it can still be read.
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<serde_json::Result<Value>>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let content_length = content_length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header")
+    })?;
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)))
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+struct Server {
+    options: Options,
+    /// The text of each open document, by URI.
+    documents: HashMap<String, String>,
+    shut_down: bool,
+}
+
+impl Server {
+    fn notify(&mut self, method: &str, params: &Value) {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.documents.insert(uri.to_string(), text.to_string());
+            }
+            // Documents are always synced in full, so the last change holds the whole text.
+            "textDocument/didChange" => {
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes.and_then(|c| c.last()?["text"].as_str()) {
+                    self.documents.insert(uri.to_string(), text.to_string());
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+            }
+            _ => (),
+        }
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "documentFormattingProvider": true,
+                    "documentRangeFormattingProvider": true,
+                    "foldingRangeProvider": true,
+                },
+                "serverInfo": { "name": "sillyfmt-lsp" },
+            })),
+            "shutdown" => {
+                self.shut_down = true;
+                Ok(Value::Null)
+            }
+            "textDocument/formatting" => {
+                let text = self.document(params)?;
+                Ok(self.format_document(text))
+            }
+            "textDocument/rangeFormatting" => {
+                let text = self.document(params)?;
+                let range = &params["range"];
+                let (start, end) = (
+                    offset(text, &range["start"]).ok_or_else(invalid_range)?,
+                    offset(text, &range["end"]).ok_or_else(invalid_range)?,
+                );
+                if start > end {
+                    return Err(invalid_range());
+                }
+                let (start, end, formatted) = format_range(text, start, end, &self.options, parse)
+                    .map_err(|e| (INTERNAL_ERROR, e.to_string()))?;
+                Ok(edits(text, start, end, formatted))
+            }
+            "textDocument/foldingRange" => {
+                let text = self.document(params)?;
+                let ranges = container_ranges(&self.options, text, parse)
+                    .into_iter()
+                    .filter_map(|(start, end)| {
+                        let start_line = position(text, start).0;
+                        // Leave the line with the closing bracket visible.
+                        let end_line = position(text, end).0.checked_sub(1)?;
+                        if end_line > start_line {
+                            Some(json!({ "startLine": start_line, "endLine": end_line }))
+                        } else {
+                            None
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                Ok(Value::Array(ranges))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("unsupported method {}", method))),
+        }
+    }
+
+    fn document(&self, params: &Value) -> Result<&str, (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        self.documents
+            .get(uri)
+            .map(|text| text.as_str())
+            .ok_or_else(|| (INVALID_PARAMS, format!("unknown document {:?}", uri)))
+    }
+
+    fn format_document(&self, text: &str) -> Value {
+        let mut output = vec![];
+        if text.trim().is_empty()
+            || do_format(
+                &mut output,
+                &self.options,
+                text.to_string(),
+                None::<io::Sink>,
+                parse,
+            )
+            .is_err()
+        {
+            return json!([]);
+        }
+        let mut formatted = String::from_utf8_lossy(&output).trim_end().to_string();
+        if text.ends_with('\n') {
+            formatted.push('\n');
+        }
+        edits(text, 0, text.len(), formatted)
+    }
+}
+
+/// The edits which replace `text[start..end]` with `formatted`, if that changes anything.
+fn edits(text: &str, start: usize, end: usize, formatted: String) -> Value {
+    if formatted == text[start..end] {
+        return json!([]);
+    }
+    json!([{
+        "range": {
+            "start": position_json(text, start),
+            "end": position_json(text, end),
+        },
+        "newText": formatted,
+    }])
+}
+
+fn invalid_range() -> (i64, String) {
+    (INVALID_PARAMS, "invalid range".to_string())
+}
+
+/// The byte offset of an LSP position, which counts characters in UTF-16 code units.
+fn offset(text: &str, position: &Value) -> Option<usize> {
+    let line = position["line"].as_u64()? as usize;
+    let character = position["character"].as_u64()? as usize;
+    let line_start = if line == 0 {
+        0
+    } else {
+        text.match_indices('\n').nth(line - 1)?.0 + 1
+    };
+    let line_text = text[line_start..].split('\n').next().unwrap_or_default();
+    let mut units = 0;
+    for (idx, c) in line_text.char_indices() {
+        if units >= character {
+            return Some(line_start + idx);
+        }
+        units += c.len_utf16();
+    }
+    // Positions past the end of the line refer to the end of the line.
+    Some(line_start + line_text.len())
+}
+
+/// The line and UTF-16 character of a byte offset.
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    let line = before.matches('\n').count();
+    (line, before[line_start..].encode_utf16().count())
+}
+
+fn position_json(text: &str, offset: usize) -> Value {
+    let (line, character) = position(text, offset);
+    json!({ "line": line, "character": character })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the server over a script of messages, returning its responses.
+    fn run(messages: &[Value]) -> (bool, Vec<Value>) {
+        let mut input = vec![];
+        for message in messages {
+            write_message(&mut input, message).unwrap();
+        }
+        run_raw(&input)
+    }
+
+    /// Runs the server over raw input, which doesn't have to hold valid messages.
+    fn run_raw(input: &[u8]) -> (bool, Vec<Value>) {
+        let mut output = vec![];
+        let clean = serve(input, &mut output, Options::default()).unwrap();
+        let mut reader = &output[..];
+        let mut responses = vec![];
+        while let Some(response) = read_message(&mut reader).unwrap() {
+            responses.push(response.unwrap());
+        }
+        (clean, responses)
+    }
+
+    fn request(id: u64, method: &str, params: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+    }
+
+    fn notification(method: &str, params: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "method": method, "params": params })
+    }
+
+    #[test]
+    fn test_session() {
+        let uri = "file:///tmp/dump.txt";
+        let text = "before\n  call(a,b,  \"ü\")\n{\n  x: 1\n}\n";
+        let doc = json!({ "uri": uri });
+        let (clean, responses) = run(&[
+            request(1, "initialize", json!({})),
+            notification("initialized", json!({})),
+            notification(
+                "textDocument/didOpen",
+                json!({ "textDocument": { "uri": uri, "text": text } }),
+            ),
+            request(
+                2,
+                "textDocument/rangeFormatting",
+                json!({
+                    "textDocument": doc,
+                    "range": {
+                        "start": { "line": 1, "character": 8 },
+                        "end": { "line": 1, "character": 10 },
+                    },
+                }),
+            ),
+            request(
+                3,
+                "textDocument/foldingRange",
+                json!({ "textDocument": doc }),
+            ),
+            notification(
+                "textDocument/didChange",
+                json!({ "textDocument": doc, "contentChanges": [{ "text": "{a:1,b:2}" }] }),
+            ),
+            request(4, "textDocument/formatting", json!({ "textDocument": doc })),
+            request(5, "textDocument/hover", json!({ "textDocument": doc })),
+            request(6, "shutdown", Value::Null),
+            notification("exit", Value::Null),
+        ]);
+        assert!(clean);
+        assert_eq!(responses.len(), 6);
+        assert_eq!(
+            responses[0]["result"]["capabilities"]["documentRangeFormattingProvider"],
+            true
+        );
+        assert_eq!(
+            responses[1]["result"],
+            json!([{
+                "range": {
+                    "start": { "line": 1, "character": 2 },
+                    "end": { "line": 1, "character": 17 },
+                },
+                "newText": "call( a, b, \"ü\" )",
+            }])
+        );
+        assert_eq!(
+            responses[2]["result"],
+            json!([{ "startLine": 2, "endLine": 3 }])
+        );
+        assert_eq!(responses[3]["result"][0]["newText"], "{ a: 1, b: 2 }");
+        assert_eq!(responses[4]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(responses[5]["id"], 6);
+    }
+
+    #[test]
+    fn test_invalid_json() {
+        let mut input = b"Content-Length: 9\r\n\r\n{\"id\": 1,".to_vec();
+        write_message(&mut input, &request(2, "initialize", json!({}))).unwrap();
+        write_message(&mut input, &request(3, "shutdown", Value::Null)).unwrap();
+        write_message(&mut input, &notification("exit", Value::Null)).unwrap();
+        let (clean, responses) = run_raw(&input);
+        assert!(clean);
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0]["id"], Value::Null);
+        assert_eq!(responses[0]["error"]["code"], PARSE_ERROR);
+        assert_eq!(responses[1]["id"], 2);
+        assert_eq!(responses[1]["result"]["serverInfo"]["name"], "sillyfmt-lsp");
+        assert_eq!(responses[2]["id"], 3);
+    }
+
+    #[test]
+    fn test_positions() {
+        let text = "aü😀b\nc";
+        let at = |line, character| offset(text, &json!({ "line": line, "character": character }));
+        assert_eq!(at(0, 2), Some(3));
+        assert_eq!(at(0, 4), Some(7));
+        assert_eq!(at(0, 99), Some(8));
+        assert_eq!(at(1, 0), Some(9));
+        assert_eq!(at(2, 0), None);
+        assert_eq!(position(text, 7), (0, 4));
+        assert_eq!(position(text, 10), (1, 1));
+    }
+}
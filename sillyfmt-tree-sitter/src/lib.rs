@@ -67,10 +67,22 @@ pub fn parse(s: String) -> (Box<dyn ParseTree>, String) {
 mod tests {
     use std::io::{Result, Write};
 
+    use sillyfmt::{BracketSpacing, Dialect, LogPrefix, Options, Query, Spacing, TrailingComma};
+
     use super::parse;
 
     fn do_format(writer: impl Write, data: String) -> Result<()> {
-        sillyfmt::do_format(writer, data, Some(std::io::stdout()), parse)
+        do_format_with_options(writer, &Default::default(), data)
+    }
+
+    fn do_format_with_options(writer: impl Write, options: &Options, data: String) -> Result<()> {
+        sillyfmt::do_format(writer, options, data, Some(std::io::stdout()), parse)
+    }
+
+    fn format_with(options: &Options, data: &str) -> String {
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(&mut output, options, data.to_string()).unwrap();
+        String::from_utf8(output).unwrap().trim().to_string()
     }
 
     #[test]
@@ -143,6 +155,85 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_labeled_container_spacing() {
+        let test_str = "struct{a:b, c:d} call(alpha, beta) get[x] {y}";
+        let mut output = Vec::with_capacity(1000);
+        do_format_with_options(
+            &mut output,
+            &Options {
+                bracket_spacing: "parens=never,braces=always".parse().unwrap(),
+                ..Default::default()
+            },
+            test_str.to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "struct{ a: b, c: d } call(alpha, beta) get[x] { y }"
+        );
+    }
+
+    #[test]
+    fn test_labeled_container_never_spaced() {
+        let test_str = "struct{a:b, c:d}";
+        let mut output = Vec::with_capacity(1000);
+        do_format_with_options(
+            &mut output,
+            &Options {
+                bracket_spacing: BracketSpacing {
+                    braces: Spacing::Never,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            test_str.to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "struct{a: b, c: d}"
+        );
+    }
+
+    #[test]
+    fn test_call() {
+        let test_str = "foo (aaaaaaaaaaaa, bbbbbbbbbbbb, cccccccccccc) + x.bar(c)";
+        let mut output = Vec::with_capacity(1000);
+        do_format(&mut output, test_str.to_string()).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "foo( aaaaaaaaaaaa, bbbbbbbbbbbb, cccccccccccc ) + x.bar(c)"
+        );
+    }
+
+    #[test]
+    fn test_long_call() {
+        let test_str = "call_something(first_argument_value, second_argument_value, nested(third_argument_value, 4))";
+        let mut output = Vec::with_capacity(1000);
+        do_format_with_options(
+            &mut output,
+            &Options {
+                trailing_commas: TrailingComma::Vertical,
+                ..Default::default()
+            },
+            test_str.to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "call_something(
+  first_argument_value,
+  second_argument_value,
+  nested( third_argument_value, 4 ),
+)"
+        );
+    }
+
     #[test]
     fn test_comma_colon_container() {
         let test_str = "{,:}";
@@ -166,4 +257,1089 @@ mod tests {
         do_format(&mut output, test_str.to_string()).unwrap();
         assert_eq!(String::from_utf8(output).unwrap().trim(), "}:");
     }
+
+    #[test]
+    fn test_url_atom() {
+        let test_str = "\"picture\":\"http://placehold.it/32x32\"";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "\"picture\": \"http://placehold.it/32x32\""
+        );
+    }
+
+    #[test]
+    fn test_url_with_commas_and_parens() {
+        let test_str = "{url: \"https://x.com/a?ids=1,2,3&b=2\"}";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "{\n  url: \"https://x.com/a?ids=1,2,3&b=2\"\n}"
+        );
+
+        let test_str =
+            "see (https://en.wikipedia.org/wiki/Rust_(programming_language)), [https://x.com/a.]";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "see( https://en.wikipedia.org/wiki/Rust_(programming_language) ),\n[ https://x.com/a. ]"
+        );
+    }
+
+    #[test]
+    fn test_location_atoms() {
+        let test_str = "[::1]:443 from 10.0.0.1:8080 at src/main.rs:12:5 by user@example.com";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap().trim(), test_str);
+    }
+
+    #[test]
+    fn test_line_comment() {
+        let test_str = "{a: 1, // first, thing\n b: 2, c: [1, 2, 3], d: \"something long\"}";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "{
+  a: 1, // first, thing
+  b: 2,
+  c: [ 1, 2, 3 ],
+  d: \"something long\"
+}"
+        );
+    }
+
+    #[test]
+    fn test_block_comment() {
+        let test_str = "foo(1 /* a,   b */, 2)";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "foo( 1 /* a,   b */, 2 )"
+        );
+    }
+
+    #[test]
+    fn test_shell_comment() {
+        let test_str = "a: 1, # note, here\nb: 2";
+        let options = Options {
+            dialect: Dialect::Shell,
+            ..Default::default()
+        };
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(&mut output, &options, test_str.to_string()).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap().trim(), test_str);
+    }
+
+    #[test]
+    fn test_statements() {
+        let test_str = "let a = 1; let b = foo(a, b); return b;";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "let a = 1;\nlet b = foo(a, b);\nreturn b;"
+        );
+    }
+
+    #[test]
+    fn test_statements_in_container() {
+        let test_str = "fn f() { let a = 1; let b = 2; } for (i = 0; i < n; i++) {}";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "fn f() {
+  let a = 1;
+  let b = 2;
+} for( i = 0; i < n; i++ ) {}"
+        );
+    }
+
+    #[test]
+    fn test_semicolons_in_strings_and_prose() {
+        let test_str =
+            "{\"css\": \"color: red; margin: 0\"}\nError: connection reset; retrying in 5s";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "{ \"css\": \"color: red; margin: 0\" }\nError: connection reset; retrying in 5s"
+        );
+    }
+
+    #[test]
+    fn test_multiline_strings() {
+        let test_str = "f(r#\"a, \"b\" (c)\"#, \"\"\"x,\n   y:  z\"\"\")";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "f(\n  r#\"a, \"b\" (c)\"#,\n  \"\"\"x,\n   y:  z\"\"\"\n)"
+        );
+    }
+
+    #[test]
+    fn test_escaped_newline_string() {
+        let test_str = "{\"about\":\"a,  b:c.\\r\\nd\\n\"}";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "{ \"about\": \"a,  b:c.\\r\\nd\\n\" }"
+        );
+    }
+
+    #[test]
+    fn test_expand_escapes() {
+        let test_str = "{\"about\":\"a,  b:c.\\nd\\n\"}";
+        let options = Options {
+            expand_escapes: true,
+            ..Default::default()
+        };
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(&mut output, &options, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "{
+  \"about\": \"a,  b:c.
+    d
+    \"
+}"
+        );
+    }
+
+    #[test]
+    fn test_xml() {
+        let test_str = "<?xml version=\"1.0\"?><soap:Envelope><soap:Body><m:Price   currency = \"USD\">1.90</m:Price><!-- a, b --><m:Note/></soap:Body></soap:Envelope>";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "<?xml version=\"1.0\"?>
+<soap:Envelope>
+  <soap:Body>
+    <m:Price currency=\"USD\">1.90</m:Price>
+    <!-- a, b -->
+    <m:Note/>
+  </soap:Body>
+</soap:Envelope>"
+        );
+    }
+
+    #[test]
+    fn test_html() {
+        let test_str = "<div><p>Hello <b>world</b></p><br><img src=\"x.png\"></div>";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "<div>
+  <p>Hello <b>world</b></p>
+  <br>
+  <img src=\"x.png\">
+</div>"
+        );
+    }
+
+    #[test]
+    fn test_html_mixed_content() {
+        let test_str = "<ul>\n  <li>One, <i>two</i> and <a href=\"#\">three</a>.</li>\n  <li><b>Four</b></li>\n</ul>";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "<ul>
+  <li>One, <i>two</i> and <a href=\"#\">three</a>.</li>
+  <li>
+    <b>Four</b>
+  </li>
+</ul>"
+        );
+    }
+
+    #[test]
+    fn test_html_raw_text() {
+        let test_str = "<div><script>if (a<b) {}</script><style>p > b { color: red }</style></div>";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "<div>
+  <script>if (a<b) {}</script>
+  <style>p > b { color: red }</style>
+</div>"
+        );
+    }
+
+    #[test]
+    fn test_markup_in_text() {
+        let test_str = "resp: <a><b>1</b></a>";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap().trim(), test_str);
+    }
+
+    #[test]
+    fn test_java_stack_trace() {
+        let test_str = "java.lang.IllegalStateException: boom at com.example.Foo.run(Foo.java:12) ~[app.jar:1.0] at com.example.Main.main(Main.java:5) ~[app.jar:1.0]
+Caused by: java.io.IOException: closed
+\tat java.io.FileInputStream.read(FileInputStream.java:233)
+\t... 2 more";
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(
+            &mut output,
+            &Options {
+                dialect: Dialect::StackTrace,
+                ..Default::default()
+            },
+            test_str.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "java.lang.IllegalStateException: boom
+  at com.example.Foo.run(Foo.java:12)   ~[app.jar:1.0]
+  at com.example.Main.main(Main.java:5) ~[app.jar:1.0]
+Caused by: java.io.IOException: closed
+  at java.io.FileInputStream.read(FileInputStream.java:233)
+  ... 2 more"
+        );
+    }
+
+    #[test]
+    fn test_python_traceback() {
+        let test_str = r#"Traceback (most recent call last):
+  File "app.py", line 10, in <module>
+    main()
+  File "lib/handlers.py", line 3, in main
+    raise ValueError("bad")
+ValueError: bad"#;
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(
+            &mut output,
+            &Options {
+                dialect: Dialect::StackTrace,
+                ..Default::default()
+            },
+            test_str.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            r#"Traceback (most recent call last):
+  File "app.py", line 10, in <module>
+    main()
+  File "lib/handlers.py", line 3, in main
+    raise ValueError("bad")
+ValueError: bad"#
+        );
+    }
+
+    #[test]
+    fn test_rust_backtrace_collapse() {
+        let test_str = "thread 'main' panicked at 'boom', src/main.rs:2:5
+stack backtrace:
+   0: app::handler
+             at ./src/main.rs:2:5
+   1: tokio::runtime::task::poll
+             at /cargo/tokio/src/runtime/task.rs:10:9
+   2: tokio::runtime::park::run
+             at /cargo/tokio/src/runtime/park.rs:40:1
+   3: app::main
+             at ./src/main.rs:9:3";
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(
+            &mut output,
+            &Options {
+                dialect: Dialect::StackTrace,
+                collapse_frames: vec!["tokio::".to_string()],
+                ..Default::default()
+            },
+            test_str.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "thread 'main' panicked at 'boom', src/main.rs:2:5
+stack backtrace:
+  0: app::handler at ./src/main.rs:2:5
+  ... 2 frames omitted
+  3: app::main    at ./src/main.rs:9:3"
+        );
+    }
+
+    #[test]
+    fn test_sql() {
+        let test_str = "select u.id, u.name, count(*) from users u left join orders o on o.user_id = u.id where u.name = 'a, b' and u.id in (select user_id from bans) group by u.id -- slow\norder by 2 desc limit 10;";
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(
+            &mut output,
+            &Options {
+                dialect: Dialect::Sql,
+                uppercase_keywords: true,
+                ..Default::default()
+            },
+            test_str.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "SELECT u.id,
+  u.name,
+  count(*)
+FROM users u
+LEFT JOIN orders o ON o.user_id = u.id
+WHERE u.name = 'a, b' AND u.id IN (
+    SELECT user_id
+    FROM bans
+  )
+GROUP BY u.id -- slow
+ORDER BY 2 DESC
+LIMIT 10;"
+        );
+    }
+
+    #[test]
+    fn test_sql_keyword_brackets() {
+        let test_str = "SELECT * FROM t WHERE id IN (SELECT id FROM u) AND n IN (1, 2); INSERT INTO t VALUES (1, 2)";
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(
+            &mut output,
+            &Options {
+                dialect: Dialect::Sql,
+                ..Default::default()
+            },
+            test_str.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "SELECT *
+FROM t
+WHERE id IN (
+    SELECT id
+    FROM u
+  ) AND n IN (1, 2);
+INSERT INTO t
+VALUES (1, 2)"
+        );
+    }
+
+    #[test]
+    fn test_sql_statements() {
+        let test_str = "insert into t (a, b) values (1, 'it''s'); select * from t";
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(
+            &mut output,
+            &Options {
+                dialect: Dialect::Sql,
+                ..Default::default()
+            },
+            test_str.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "insert into t(a, b)
+values (1, 'it''s');
+select *
+from t"
+        );
+    }
+
+    #[test]
+    fn test_logfmt() {
+        let test_str = "level=info msg=\"request done\" dur=12ms user={id:3,name:x}";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "level=info msg=\"request done\" dur=12ms user={ id: 3, name: x }"
+        );
+    }
+
+    #[test]
+    fn test_logfmt_long() {
+        let test_str = "ts=2020-01-01T10:00:00Z level=info msg=\"request done\" path=/api/v1/users err= user={id:3,name:x,roles:[admin,dev]}";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "ts    = 2020-01-01T10:00:00Z
+level = info
+msg   = \"request done\"
+path  = /api/v1/users
+err   =
+user  = {
+  id: 3,
+  name: x,
+  roles: [ admin, dev ]
+}"
+        );
+    }
+
+    #[test]
+    fn test_logfmt_unicode_keys() {
+        let test_str = "ts=2020-01-01T10:00:00Z niveau=info durée=12ms größe=3 msg=\"request done\" user={id:3,name:x,roles:[admin,dev]}";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "ts     = 2020-01-01T10:00:00Z
+niveau = info
+durée  = 12ms
+größe  = 3
+msg    = \"request done\"
+user   = {
+  id: 3,
+  name: x,
+  roles: [ admin, dev ]
+}"
+        );
+    }
+
+    #[test]
+    fn test_align_values() {
+        let test_str = "{name: \"sillyfmt\", version: 1, dependencies: {tree_sitter: 0.16, regex: 1.3}, a_very_long_key_name_that_goes_on_and_on: 3}";
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(
+            &mut output,
+            &Options {
+                align_values: true,
+                ..Default::default()
+            },
+            test_str.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "{
+  name:         \"sillyfmt\",
+  version:      1,
+  dependencies: { tree_sitter: 0.16, regex: 1.3 },
+  a_very_long_key_name_that_goes_on_and_on: 3
+}"
+        );
+    }
+
+    #[test]
+    fn test_align_values_non_ascii() {
+        let test_str = "{é: 1, ab: 2, ünïcödé: 3, description: \"some long text\"}";
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(
+            &mut output,
+            &Options {
+                align_values: true,
+                ..Default::default()
+            },
+            test_str.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "{
+  é:           1,
+  ab:          2,
+  ünïcödé:     3,
+  description: \"some long text\"
+}"
+        );
+    }
+
+    #[test]
+    fn test_align_assignments() {
+        let test_str = "{a = 1, bbbbbbbb = [1, 2, 3], cc => \"hello there friend\", ddd => 4}";
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(
+            &mut output,
+            &Options {
+                align_values: true,
+                ..Default::default()
+            },
+            test_str.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "{
+  a        = 1,
+  bbbbbbbb = [ 1, 2, 3 ],
+  cc  => \"hello there friend\",
+  ddd => 4
+}"
+        );
+    }
+
+    #[test]
+    fn test_table() {
+        let test_str =
+            "friends: [{id: 0, name: Chandler}, {id: 1, name: Herrera}, {id: 22, name: Elva}]";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "friends: [
+  { id: 0,  name: Chandler },
+  { id: 1,  name: Herrera },
+  { id: 22, name: Elva }
+]"
+        );
+    }
+
+    #[test]
+    fn test_table_non_ascii() {
+        let test_str =
+            "[{name: \"日本語\", id: 0}, {name: \"abc\", id: 1}, {name: \"abcdefg\", id: 2}]";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "[
+  { name: \"日本語\",     id: 0 },
+  { name: \"abc\",     id: 1 },
+  { name: \"abcdefg\", id: 2 }
+]"
+        );
+    }
+
+    #[test]
+    fn test_table_mismatched_keys() {
+        let test_str = "[{id: 0, name: Chandler}, {id: 1, nick: Herrera}, {id: 22, name: Elva}]";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "[
+  { id: 0, name: Chandler },
+  { id: 1, nick: Herrera },
+  { id: 22, name: Elva }
+]"
+        );
+    }
+
+    #[test]
+    fn test_table_reordered_keys() {
+        let test_str = "[{id: 0, name: Chandler}, {name: Herrera, id: 1}, {id: 22, name: Elva}]";
+        let mut output = Vec::with_capacity(100);
+        do_format(&mut output, test_str.to_string()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "[
+  { id: 0, name: Chandler },
+  { name: Herrera, id: 1 },
+  { id: 22, name: Elva }
+]"
+        );
+    }
+
+    #[test]
+    fn test_trailing_commas_vertical() {
+        let options = Options {
+            trailing_commas: TrailingComma::Vertical,
+            ..Default::default()
+        };
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(
+            &mut output,
+            &options,
+            "[aaaaaaaaaaaaaaa, bbbbbbbbbbbbbbbbbbbb // hi\n]".to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "[
+  aaaaaaaaaaaaaaa,
+  bbbbbbbbbbbbbbbbbbbb, // hi
+]"
+        );
+
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(&mut output, &options, "f(a, b,) + g(x,)".to_string()).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap().trim(), "f(a, b) + g(x,)");
+    }
+
+    #[test]
+    fn test_drop_empty_elements() {
+        let options = Options {
+            drop_empty_elements: true,
+            ..Default::default()
+        };
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(&mut output, &options, "a, , c".to_string()).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap().trim(), "a, c");
+
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(&mut output, &options, "[, a, , b, , ]".to_string()).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap().trim(), "[ a, b, ]");
+    }
+
+    #[test]
+    fn test_collapse_repeats() {
+        let test_str = "[0, 0, 0, 0, 0, 0, 1, 2, 2, {a: 1}, {a: 1}, {a: 1}, {a: 1}]";
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(
+            &mut output,
+            &Options {
+                collapse_repeats: true,
+                ..Default::default()
+            },
+            test_str.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "[ 0 × 6, 1, 2, 2, {a: 1} × 4 ]"
+        );
+    }
+
+    #[test]
+    fn test_max_elements() {
+        let test_str =
+            "{data: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17], ok: true}";
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(
+            &mut output,
+            &Options {
+                max_elements: Some(3),
+                ..Default::default()
+            },
+            test_str.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "{
+  data: [ 1, 2, 3, ... 14 more ],
+  ok: true
+}"
+        );
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let test_str =
+            "{name: x, tags: [a, b, c], friends: [{id: 0}, {id: 1}], empty: [], one: (a)}";
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(
+            &mut output,
+            &Options {
+                max_depth: Some(1),
+                ..Default::default()
+            },
+            test_str.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "{
+  name: x,
+  tags: [… 3 items],
+  friends: [… 2 items],
+  empty: [],
+  one: (… 1 item)
+}"
+        );
+    }
+
+    #[test]
+    fn test_select() {
+        let test_str = r#"[{"id": 0, "friends": [{"id": 1, "name": "Chandler Robinson"}, {"id": 2, "name": "Herrera Hess"}]}, {"id": 3, "extra": Some(Foo {guid: abc, n: [1, 2]})}]"#;
+        let select = |query: &str| {
+            format_with(
+                &Options {
+                    select: Some(query.parse().unwrap()),
+                    ..Default::default()
+                },
+                test_str,
+            )
+        };
+        assert_eq!(select(".[0].friends[1].name"), "\"Herrera Hess\"");
+        assert_eq!(select("[*].id"), "0\n3");
+        assert_eq!(select("**.id"), "0\n1\n2\n3");
+        assert_eq!(select("**.guid"), "abc");
+        assert_eq!(select("[1].extra"), "Some( Foo{ guid: abc, n: [1, 2] } )");
+        assert_eq!(select("[1].extra.n[*]"), "1\n2");
+        assert_eq!(select(".missing"), "");
+    }
+
+    #[test]
+    fn test_to_json() {
+        let to_json = |input: &str, select: Option<&str>| {
+            format_with(
+                &Options {
+                    to_json: true,
+                    select: select.map(|q| q.parse().unwrap()),
+                    ..Default::default()
+                },
+                input,
+            )
+        };
+        assert_eq!(
+            to_json(
+                r#"Foo { id: 12, name: "a \"b\"", tags: [x, -1.5e3], parent: None, inner: Some(3) }"#,
+                None
+            ),
+            r#"{
+  "__type": "Foo",
+  "id": 12,
+  "name": "a \"b\"",
+  "tags": [
+    "x",
+    -1.5e3
+  ],
+  "parent": null,
+  "inner": {
+    "__type": "Some",
+    "__values": [
+      3
+    ]
+  }
+}"#
+        );
+        assert_eq!(
+            to_json(
+                "{'ok': True, 'items': [], 'extra': {}, 'pair': (1, 'two')}",
+                None
+            ),
+            r#"{
+  "ok": true,
+  "items": [],
+  "extra": {},
+  "pair": [
+    1,
+    "two"
+  ]
+}"#
+        );
+        assert_eq!(
+            to_json("[1, a: 2, 007]", None),
+            r#"[
+  1,
+  {
+    "a": 2
+  },
+  "007"
+]"#
+        );
+        assert_eq!(to_json("[{n: 1}, {n: false}]", Some("[*].n")), "1\nfalse");
+        assert_eq!(
+            to_json(r#"{"a\"b": 1, 'c\\d': 2}"#, None),
+            r#"{
+  "a\"b": 1,
+  "c\\d": 2
+}"#
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let diff = |old: &str, new: &str| {
+            let mut output = Vec::with_capacity(100);
+            sillyfmt::do_diff(
+                &mut output,
+                &Options::default(),
+                old.to_string(),
+                new.to_string(),
+                false,
+                parse,
+            )
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(
+            diff(
+                "Foo { id: 1, name: \"a\", tags: [x, y, z], owner: Bar { n: 1 } }",
+                "Foo {\n  name: \"a\",\n  id: 2,\n  tags: [z, x, y, w],\n  extra: true,\n  owner: Bar { n: 1 },\n}",
+            ),
+            r#"  Foo {
+~   id: 1 → 2
+    name: "a"
+    tags: [
+>     z
+      x
+      y
++     w
+    ]
+    owner: Bar { n: 1 }
++   extra: true
+  }
+"#
+        );
+        assert_eq!(
+            diff("[a, {b: 1}, c]", "[a, {b: 2}, c]"),
+            "  [\n    a\n    {\n~     b: 1 → 2\n    }\n    c\n  ]\n"
+        );
+        assert_eq!(diff("(1,2)", "(1, 2)"), "  (1, 2)\n");
+    }
+
+    #[test]
+    fn test_log_prefix() {
+        let format = |input: &str, log_prefix: LogPrefix| {
+            format_with(
+                &Options {
+                    log_prefix: Some(log_prefix),
+                    ..Default::default()
+                },
+                input,
+            )
+        };
+        let test_str = "2020-05-05T10:00:00Z INFO [worker-3] state={id: 1, name: \"a very long name which won't fit\", items: [1, 2, 3]}\n\
+            2020-05-05T10:00:01.123Z WARN [main] done\n\
+            ERROR {a: 1,\n  b: 2}";
+        assert_eq!(
+            format(test_str, LogPrefix::default()),
+            r#"2020-05-05T10:00:00Z INFO [worker-3]
+  state = {
+    id: 1,
+    name: "a very long name which won't fit",
+    items: [ 1, 2, 3 ]
+  }
+2020-05-05T10:00:01.123Z WARN [main] done
+ERROR { a: 1, b: 2 }"#
+        );
+        assert_eq!(
+            format("[1, 2]\n#12 {x: 1}", "#\\d+".parse().unwrap()),
+            "[1, 2]\n#12 {x: 1}"
+        );
+    }
+
+    #[test]
+    fn test_embedded_payloads() {
+        let format = |input: &str| {
+            format_with(
+                &Options {
+                    embedded_payloads: true,
+                    ..Default::default()
+                },
+                input,
+            )
+        };
+        assert_eq!(
+            format(
+                r#"Got response: {"ok":true,"items":[{"id": 1, "name": "first"}, {"id": 2, "name": "second"}]} after 12ms"#
+            ),
+            r#"Got response:
+{
+  "ok": true,
+  "items": [
+    { "id": 1, "name": "first" },
+    { "id": 2, "name": "second" }
+  ]
+}
+after 12ms"#
+        );
+        assert_eq!(
+            format("status (ok)  code=[1,2] Some(1, 2) done"),
+            "status (ok)  code=[1, 2] Some(1, 2) done"
+        );
+        assert_eq!(format("[1,2,3]"), "[ 1, 2, 3 ]");
+    }
+
+    #[test]
+    fn test_unwrap_encoded_strings() {
+        let test_str = r#"{"id": 7, "payload": "{\"a\":1,\"b\":[2,3],\"inner\":\"[\\\"x\\\", \\\"y\\\"]\"}", "n": "say \"hi\""}"#;
+        let format = |unwrap_encoded_strings| {
+            format_with(
+                &Options {
+                    unwrap_encoded_strings,
+                    ..Default::default()
+                },
+                test_str,
+            )
+        };
+        assert_eq!(
+            format(true),
+            r#"{
+  "id": 7,
+  "payload": (string) {
+    "a": 1,
+    "b": [2, 3],
+    "inner": (string) [ "x", "y" ]
+  },
+  "n": "say \"hi\""
+}"#
+        );
+        assert!(!format(false).contains("(string)"));
+    }
+
+    #[test]
+    fn test_blobs() {
+        let test_str = r#"{data: [72, 101, 108, 108, 111, 44, 32, 119, 111, 114, 108, 100, 33, 32, 72, 105, 32, 116, 104, 101, 114, 101], key: "SGVsbG8gd29ybGQsIHRoaXMgaXMgYSB0ZXN0IG9mIGJhc2U2NCBkZWNvZGluZyBpbiBzaWxseWZtdCB3aGljaCBpcyBsb25n", raw: b"\x00\x01", id: 0123456789abcdef0123456789abcdef, tok: SGVsbG8gd29ybGQgdGhpcyBpcyBhIHRlc3Q=}"#;
+        let format = |decode_blobs, full_blobs| {
+            format_with(
+                &Options {
+                    decode_blobs,
+                    full_blobs,
+                    ..Default::default()
+                },
+                test_str,
+            )
+        };
+        assert_eq!(
+            format(false, true),
+            r#"{
+  data: [72, 101, 108, 108, 111, 44, 32, 119, 111, 114, 108, 100, 33, 32, 72, 105, 32, 116, 104, 101, 114, 101],
+  key: "SGVsbG8gd29ybGQsIHRoaXMgaXMgYSB0ZXN0IG9mIGJhc2U2NCBkZWNvZGluZyBpbiBzaWxseWZtdCB3aGljaCBpcyBsb25n",
+  raw: b"\x00\x01",
+  id: 0123456789abcdef0123456789abcdef,
+  tok: SGVsbG8gd29ybGQgdGhpcyBpcyBhIHRlc3Q=
+}"#
+        );
+        assert_eq!(
+            format(false, false),
+            r#"{
+  data: [72, 101, 108, 108, 111, 44, 32, 119, 111, 114, 108, 100, 33, 32, … 22 bytes],
+  key: "SGVsbG8gd29ybGQsIHRoaXMgaXMgYSB0ZXN0IG9mIGJhc2U2NCBkZWNvZGluZyBp…" (72 bytes),
+  raw: b"\x00\x01",
+  id: 0123456789abcdef0123456789abcdef,
+  tok: SGVsbG8gd29ybGQgdGhpcyBpcyBhIHRlc3Q=
+}"#
+        );
+        assert_eq!(
+            format(true, false),
+            r#"{
+  data: (bytes) "Hello, world! Hi there",
+  key: (base64) "Hello world, this is a test of base64 decoding in sillyfmt which is long",
+  raw: b"\x00\x01",
+  id: 0123456789abcdef0123456789abcdef,
+  tok: (base64) "Hello world this is a test"
+}"#
+        );
+
+        let test_str = "[12, 255, 0, 7, 128, 3, 64, 9, 200, 1, 33, 0, 5, 250, 2, 8, 100, 4, 16, 32, 48, 96, 6]";
+        assert_eq!(
+            format_with(&Options::default(), test_str),
+            "[12, 255, 0, 7, 128, 3, 64, 9, 200, 1, 33, 0, 5, 250, 2, 8, 100, 4, … 23 bytes]"
+        );
+        assert_eq!(
+            format_with(
+                &Options {
+                    full_blobs: true,
+                    ..Default::default()
+                },
+                test_str
+            ),
+            test_str
+        );
+    }
+
+    #[test]
+    fn test_format_range() {
+        let format_range = |data: &str, start: usize, end: usize| {
+            let (start, end, text) =
+                sillyfmt::format_range(data, start, end, &Options::default(), parse).unwrap();
+            (data[start..end].to_string(), text)
+        };
+        let data = "config:\n    Foo { name: \"a long enough name\", items: [1,2,3], nested: Some((1,  2)) }\nnext: [a,b]";
+        let offset = |needle: &str| data.find(needle).unwrap();
+        assert_eq!(
+            format_range(data, offset("items"), offset("items")),
+            (
+                data[offset("Foo")..offset("\nnext")].to_string(),
+                r#"Foo{
+      name: "a long enough name",
+      items: [ 1, 2, 3 ],
+      nested: Some( (1, 2) )
+    }"#
+                .to_string()
+            )
+        );
+        assert_eq!(
+            format_range(data, offset("2,3"), offset("2,3") + 1),
+            ("[1,2,3]".to_string(), "[ 1, 2, 3 ]".to_string())
+        );
+        assert_eq!(
+            format_range(data, offset("1,  2"), offset("1,  2")),
+            ("(1,  2)".to_string(), "(1, 2)".to_string())
+        );
+        assert_eq!(
+            format_range(data, offset("next"), offset("next") + 2),
+            ("next: [a,b]".to_string(), "next: [a, b]".to_string())
+        );
+
+        let options = Options::default();
+        for (data, start, end) in [("[a,b]", 3, 2), ("[a,b]", 0, 6), ("[é]", 2, 2)] {
+            let err = sillyfmt::format_range(data, start, end, &options, parse).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_line_width() {
+        let test_str = "level=info msg=\"request done\" dur=12ms";
+        let mut output = Vec::with_capacity(100);
+        do_format_with_options(
+            &mut output,
+            &Options {
+                line_width: Some(30),
+                ..Default::default()
+            },
+            test_str.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            "level = info\nmsg   = \"request done\"\ndur   = 12ms"
+        );
+
+        let width = |line_width| Options {
+            line_width: Some(line_width),
+            ..Default::default()
+        };
+        let test_str = "config: {name: \"sillyfmt\", version: 1, deps: [regex, tree_sitter]}";
+        assert_eq!(
+            format_with(&width(160), test_str),
+            "config: { name: \"sillyfmt\", version: 1, deps: [ regex, tree_sitter ] }"
+        );
+        assert_eq!(
+            format_with(&width(40), test_str),
+            "config: {
+  name: \"sillyfmt\",
+  version: 1,
+  deps: [
+    regex,
+    tree_sitter
+  ]
+}"
+        );
+        let test_str = "call_something(first_argument_value, second_argument_value, nested(x, 4))";
+        assert_eq!(
+            format_with(&width(40), test_str),
+            "call_something(
+  first_argument_value,
+  second_argument_value,
+  nested(x, 4)
+)"
+        );
+    }
+
+    #[test]
+    fn test_brackets_closed() {
+        let options = Options::default();
+        assert!(sillyfmt::brackets_closed("{a: [1, 2]}\n", &options));
+        assert!(sillyfmt::brackets_closed("Foo(\n  x,\n)\n", &options));
+        assert!(!sillyfmt::brackets_closed("{a: [1, 2]\n", &options));
+        assert!(!sillyfmt::brackets_closed("just some text\n", &options));
+        assert!(!sillyfmt::brackets_closed("{msg: \"a } b\"\n", &options));
+        assert!(!sillyfmt::brackets_closed("{a: 1 // }\n", &options));
+        assert!(sillyfmt::brackets_closed(
+            "{url: http://x.io/a}\n",
+            &options
+        ));
+    }
+
+    #[test]
+    fn test_parse_query() {
+        assert!("**.guid".parse::<Query>().is_ok());
+        assert!("friends[1]".parse::<Query>().is_ok());
+        assert!(".friends[x]".parse::<Query>().is_err());
+        assert!(".friends[1".parse::<Query>().is_err());
+        assert!("[0]name".parse::<Query>().is_err());
+    }
 }
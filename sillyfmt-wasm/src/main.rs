@@ -10,7 +10,7 @@ use stdweb::web::document;
 use stdweb::web::event::InputEvent;
 use stdweb::web::html_element::TextAreaElement;
 
-use sillyfmt::{silly_format, ParseCursor, ParseNode, ParseTree};
+use sillyfmt::{silly_format, Options, ParseCursor, ParseNode, ParseTree};
 
 macro_rules! enclose {
     ( ($( $x:ident ),*) $y:expr ) => {
@@ -123,6 +123,7 @@ fn run_format(s: String, debug: bool) -> String {
     let _ = silly_format(
         Cursor::new(s),
         Cursor::new(&mut out),
+        &Options::default(),
         false,
         if debug {
             Some(Cursor::new(&mut err))
@@ -0,0 +1,191 @@
+//! Binary data in dumps: hex and base64 strings, byte strings like `b"\x00\x01"` and arrays of
+//! bytes like `[12, 255, 0]`. These are kept on one line, since laying them out element by
+//! element just takes up space, and are cut short unless `full_blobs` is set.
+
+use crate::{Options, R};
+
+/// Unless `full_blobs` is set, blobs are cut short once they're longer than this many characters.
+const BLOB_WIDTH: usize = 64;
+
+/// Hex and base64 strings shorter than this are more likely to be ids or words.
+const MIN_ENCODED_LEN: usize = 32;
+
+/// Arrays with fewer elements than this are left alone, even if they're all bytes.
+const MIN_BYTE_ARRAY_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    Hex,
+    Base64,
+    /// A byte string literal, as in `b"\x00\x01"`.
+    Escaped,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Hex => "hex",
+            Encoding::Base64 => "base64",
+            Encoding::Escaped => "bytes",
+        }
+    }
+}
+
+/// Whether `s`, a string literal or a bare token, is a blob.
+pub(crate) fn is_blob(s: &str) -> bool {
+    parse(s).is_some()
+}
+
+/// Splits a blob into its quotes and body and decodes it.
+fn parse(s: &str) -> Option<(Encoding, &str, &str, &str, Vec<u8>)> {
+    if let Some(body) = s.strip_prefix("b\"").and_then(|s| s.strip_suffix('"')) {
+        return Some((Encoding::Escaped, "b\"", body, "\"", unescape_bytes(body)?));
+    }
+    let (open, body, close) = match s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(body) => ("\"", body, "\""),
+        None => ("", s, ""),
+    };
+    if body.len() < MIN_ENCODED_LEN {
+        return None;
+    }
+    if let Some(bytes) = decode_hex(body.strip_prefix("0x").unwrap_or(body)) {
+        return Some((Encoding::Hex, open, body, close, bytes));
+    }
+    let has = |f: fn(&char) -> bool| body.chars().any(|c| f(&c));
+    // Long words and identifiers are valid base64 too, so require a mix of characters.
+    let is_mixed =
+        has(char::is_ascii_digit) && has(char::is_ascii_uppercase) && has(char::is_ascii_lowercase);
+    if is_mixed || body.contains(['+', '/', '=']) {
+        let bytes = base64::decode(body).ok()?;
+        return Some((Encoding::Base64, open, body, close, bytes));
+    }
+    None
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// The bytes of the body of a byte string literal, or `None` if it has an escape which isn't
+/// understood.
+fn unescape_bytes(s: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        out.push(match chars.next()? {
+            'n' => b'\n',
+            't' => b'\t',
+            'r' => b'\r',
+            '0' => b'\0',
+            c @ ('\\' | '"' | '\'') => c as u8,
+            'x' => {
+                let hex = chars.as_str().get(..2)?;
+                chars.nth(1);
+                u8::from_str_radix(hex, 16).ok()?
+            }
+            _ => return None,
+        });
+    }
+    Some(out)
+}
+
+/// The bytes as text, if they're valid UTF-8 without any control characters other than
+/// whitespace.
+fn printable(bytes: &[u8]) -> Option<&str> {
+    let text = std::str::from_utf8(bytes).ok()?;
+    let is_printable = |c: char| !c.is_control() || c == '\n' || c == '\t' || c == '\r';
+    if text.is_empty() || !text.chars().all(is_printable) {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+/// Formats a blob found by [`is_blob`]: decoded if `decode_blobs` is set and it's text, and
+/// otherwise cut short if it's long and `full_blobs` isn't set.
+pub(crate) fn format_string(s: &str, options: &Options) -> R {
+    let (encoding, open, body, close, bytes) = match parse(s) {
+        Some(parsed) => parsed,
+        None => return R::String(s.to_string()),
+    };
+    if let Some(text) = printable(&bytes).filter(|_| options.decode_blobs) {
+        return R::String(format!("({}) {:?}", encoding.name(), text));
+    }
+    match body
+        .char_indices()
+        .nth(BLOB_WIDTH)
+        .filter(|_| !options.full_blobs)
+    {
+        Some((idx, _)) => R::String(format!(
+            "{}{}…{} ({} bytes)",
+            open,
+            &body[..idx],
+            close,
+            bytes.len()
+        )),
+        None => R::String(s.to_string()),
+    }
+}
+
+/// Formats a container whose elements are all bytes on a single line, given the elements and
+/// its brackets. Returns `None` if it isn't such a container.
+pub(crate) fn format_byte_array(
+    elements: &[&[R]],
+    open: char,
+    close: char,
+    options: &Options,
+) -> Option<Vec<R>> {
+    let elements = match elements {
+        [rest @ .., []] => rest,
+        elements => elements,
+    };
+    if elements.len() < MIN_BYTE_ARRAY_LEN {
+        return None;
+    }
+    let mut bytes = vec![];
+    let mut texts = vec![];
+    for element in elements {
+        // Single digits come through as chars rather than strings.
+        let text = match element {
+            [R::String(s)] => s.clone(),
+            [R::Char(c)] => c.to_string(),
+            _ => return None,
+        };
+        bytes.push(match text.strip_prefix("0x") {
+            Some(hex) => u8::from_str_radix(hex, 16).ok()?,
+            None => text.parse::<u8>().ok()?,
+        });
+        texts.push(text);
+    }
+
+    if let Some(text) = printable(&bytes).filter(|_| options.decode_blobs) {
+        return Some(vec![R::String(format!(
+            "({}) {:?}",
+            Encoding::Escaped.name(),
+            text
+        ))]);
+    }
+    let mut body = String::new();
+    for (idx, text) in texts.iter().enumerate() {
+        if !options.full_blobs && body.len() + text.len() > BLOB_WIDTH {
+            body.push_str(&format!(", … {} bytes", bytes.len()));
+            break;
+        }
+        if idx != 0 {
+            body.push_str(", ");
+        }
+        body.push_str(text);
+    }
+    Some(vec![R::Char(open), R::String(body), R::Char(close)])
+}
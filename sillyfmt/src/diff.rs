@@ -0,0 +1,407 @@
+//! Structural diffs between two inputs. Keyed entries are matched up by their keys and other
+//! elements by their contents, so reformatting or reordering doesn't show up as a wall of changed
+//! lines.
+
+use std::collections::HashMap;
+use std::io::{Result, Write};
+
+use crate::query::Value;
+
+/// Unchanged values are summarized on one line, cut off at this many characters.
+const SUMMARY_WIDTH: usize = 60;
+
+/// Matching up unkeyed elements is quadratic, so past this many pairs of elements they're just
+/// compared in order.
+const MAX_ALIGN_PAIRS: usize = 1 << 22;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mark {
+    Same,
+    Removed,
+    Added,
+    Changed,
+    Moved,
+}
+
+impl Mark {
+    fn symbol(self) -> char {
+        match self {
+            Mark::Same => ' ',
+            Mark::Removed => '-',
+            Mark::Added => '+',
+            Mark::Changed => '~',
+            Mark::Moved => '>',
+        }
+    }
+
+    fn color(self) -> Option<&'static str> {
+        match self {
+            Mark::Same => None,
+            Mark::Removed => Some("31"),
+            Mark::Added => Some("32"),
+            Mark::Changed => Some("33"),
+            Mark::Moved => Some("36"),
+        }
+    }
+}
+
+struct Line {
+    mark: Mark,
+    depth: usize,
+    text: String,
+}
+
+/// The values being compared, along with the text they point into.
+struct Differ<'a> {
+    old: &'a str,
+    new: &'a str,
+    lines: Vec<Line>,
+}
+
+/// How an unkeyed element of the old value lines up with the new one.
+enum Op {
+    Same(usize, usize),
+    Removed(usize),
+    Added(usize),
+}
+
+pub(crate) fn write_diff(
+    old: (&Value, &str),
+    new: (&Value, &str),
+    color: bool,
+    mut writer: impl Write,
+) -> Result<()> {
+    let mut differ = Differ {
+        old: old.1,
+        new: new.1,
+        lines: vec![],
+    };
+    differ.diff_value(
+        None,
+        old.0.unwrap_top_level(),
+        new.0.unwrap_top_level(),
+        false,
+        0,
+    );
+    for line in differ.lines {
+        let text = format!(
+            "{} {}{}",
+            line.mark.symbol(),
+            "  ".repeat(line.depth),
+            line.text
+        );
+        match line.mark.color() {
+            Some(code) if color => writeln!(writer, "\x1b[{}m{}\x1b[0m", code, text)?,
+            _ => writeln!(writer, "{}", text)?,
+        }
+    }
+    Ok(())
+}
+
+impl<'a> Differ<'a> {
+    fn push(&mut self, mark: Mark, depth: usize, text: String) {
+        self.lines.push(Line { mark, depth, text });
+    }
+
+    fn diff_value(
+        &mut self,
+        key: Option<&str>,
+        old: &Value,
+        new: &Value,
+        moved: bool,
+        depth: usize,
+    ) {
+        let prefix = key.map(|k| format!("{}: ", k)).unwrap_or_default();
+        let same = if moved { Mark::Moved } else { Mark::Same };
+        if canonical(old, self.old) == canonical(new, self.new) {
+            self.push(same, depth, format!("{}{}", prefix, summary(new, self.new)));
+            return;
+        }
+
+        let is_container = |v: &Value| v.open.is_some();
+        if is_container(old)
+            && is_container(new)
+            && old.open == new.open
+            && label(old, self.old) == label(new, self.new)
+        {
+            let open = old.open.unwrap();
+            let header = match label(new, self.new) {
+                Some(label) => format!("{}{} {}", prefix, label, open),
+                None => format!("{}{}", prefix, open),
+            };
+            self.push(same, depth, header);
+            self.diff_elements(old, new, depth + 1);
+            self.push(same, depth, closing(open).to_string());
+        } else if !is_container(old) && !is_container(new) {
+            self.push(
+                Mark::Changed,
+                depth,
+                format!(
+                    "{}{} → {}",
+                    prefix,
+                    summary(old, self.old),
+                    summary(new, self.new)
+                ),
+            );
+        } else {
+            self.push(
+                Mark::Removed,
+                depth,
+                format!("{}{}", prefix, summary(old, self.old)),
+            );
+            self.push(
+                Mark::Added,
+                depth,
+                format!("{}{}", prefix, summary(new, self.new)),
+            );
+        }
+    }
+
+    fn diff_elements(&mut self, old: &Value, new: &Value, depth: usize) {
+        let is_keyed = |v: &Value| v.elements.iter().all(|(key, _)| key.is_some());
+        if is_keyed(old) && is_keyed(new) {
+            self.diff_fields(old, new, depth);
+            return;
+        }
+
+        let old_keys = old
+            .elements
+            .iter()
+            .map(|e| canonical_element(e, self.old))
+            .collect::<Vec<_>>();
+        let new_keys = new
+            .elements
+            .iter()
+            .map(|e| canonical_element(e, self.new))
+            .collect::<Vec<_>>();
+        let ops = align(&old_keys, &new_keys);
+
+        // An element which was removed in one place and added in another has moved.
+        let mut removed = HashMap::<&str, Vec<usize>>::new();
+        for op in &ops {
+            if let Op::Removed(i) = op {
+                removed.entry(&old_keys[*i]).or_default().push(*i);
+            }
+        }
+        let mut moved_from = HashMap::new();
+        for op in &ops {
+            if let Op::Added(j) = op {
+                if let Some(i) = removed.get_mut(&*new_keys[*j]).and_then(|is| is.pop()) {
+                    moved_from.insert(i, *j);
+                }
+            }
+        }
+        let moved_to = moved_from.values().copied().collect::<Vec<_>>();
+
+        let mut idx = 0;
+        while idx < ops.len() {
+            match ops[idx] {
+                Op::Same(_, j) => self.push_element(Mark::Same, &new.elements[j], false, depth),
+                Op::Removed(i) if moved_from.contains_key(&i) => (),
+                Op::Added(j) if moved_to.contains(&j) => {
+                    self.push_element(Mark::Moved, &new.elements[j], false, depth)
+                }
+                Op::Removed(_) | Op::Added(_) => {
+                    // Pair up a run of removals with the additions after it, since those are
+                    // most likely the same elements with changes.
+                    let mut removals = vec![];
+                    let mut additions = vec![];
+                    while let Some(op) = ops.get(idx) {
+                        match op {
+                            Op::Removed(i) if !moved_from.contains_key(i) => removals.push(*i),
+                            Op::Added(j) if !moved_to.contains(j) => additions.push(*j),
+                            Op::Removed(_) => (),
+                            _ => break,
+                        }
+                        idx += 1;
+                    }
+                    let paired = removals.len().min(additions.len());
+                    for (&i, &j) in removals.iter().zip(&additions) {
+                        let ((old_key, old_value), (new_key, new_value)) =
+                            (&old.elements[i], &new.elements[j]);
+                        if old_key == new_key {
+                            self.diff_value(old_key.as_deref(), old_value, new_value, false, depth);
+                        } else {
+                            self.push_element(Mark::Removed, &old.elements[i], true, depth);
+                            self.push_element(Mark::Added, &new.elements[j], false, depth);
+                        }
+                    }
+                    for &i in &removals[paired..] {
+                        self.push_element(Mark::Removed, &old.elements[i], true, depth);
+                    }
+                    for &j in &additions[paired..] {
+                        self.push_element(Mark::Added, &new.elements[j], false, depth);
+                    }
+                    continue;
+                }
+            }
+            idx += 1;
+        }
+    }
+
+    /// Matches up entries by key, noting the ones whose position relative to the others has
+    /// changed.
+    fn diff_fields(&mut self, old: &Value, new: &Value, depth: usize) {
+        let key = |(key, _): &(Option<String>, Value)| key.clone().unwrap_or_default();
+        let new_keys = new.elements.iter().map(key).collect::<Vec<_>>();
+        let mut unmatched = (0..new.elements.len()).collect::<Vec<_>>();
+        let mut matches = vec![];
+        for (i, element) in old.elements.iter().enumerate() {
+            let k = key(element);
+            let pos = unmatched.iter().position(|&j| new_keys[j] == k);
+            matches.push((i, pos.map(|pos| unmatched.remove(pos))));
+        }
+
+        let common = matches.iter().filter_map(|(_, j)| *j).collect::<Vec<_>>();
+        let mut sorted = common.clone();
+        sorted.sort_unstable();
+        let in_order = lcs(&common, &sorted)
+            .into_iter()
+            .filter_map(|op| match op {
+                Op::Same(i, _) => Some(common[i]),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        for (i, j) in matches {
+            let (k, old_value) = &old.elements[i];
+            match j {
+                Some(j) => {
+                    let moved = !in_order.contains(&j);
+                    self.diff_value(k.as_deref(), old_value, &new.elements[j].1, moved, depth);
+                }
+                None => self.push_element(Mark::Removed, &old.elements[i], true, depth),
+            }
+        }
+        for j in unmatched {
+            self.push_element(Mark::Added, &new.elements[j], false, depth);
+        }
+    }
+
+    fn push_element(
+        &mut self,
+        mark: Mark,
+        (key, value): &(Option<String>, Value),
+        is_old: bool,
+        depth: usize,
+    ) {
+        let data = if is_old { self.old } else { self.new };
+        let text = match key {
+            Some(key) => format!("{}: {}", key, summary(value, data)),
+            None => summary(value, data),
+        };
+        self.push(mark, depth, text);
+    }
+}
+
+/// Lines up two lists of elements, keeping as many of them in place as possible.
+fn align<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Op> {
+    if old.len().saturating_mul(new.len()) <= MAX_ALIGN_PAIRS {
+        return lcs(old, new);
+    }
+    let mut ops = vec![];
+    for idx in 0..old.len().max(new.len()) {
+        match (idx < old.len(), idx < new.len()) {
+            (true, true) if old[idx] == new[idx] => ops.push(Op::Same(idx, idx)),
+            (true, true) => {
+                ops.push(Op::Removed(idx));
+                ops.push(Op::Added(idx));
+            }
+            (true, false) => ops.push(Op::Removed(idx)),
+            _ => ops.push(Op::Added(idx)),
+        }
+    }
+    ops
+}
+
+/// The longest common subsequence of `old` and `new`, as the steps to get from one to the other.
+fn lcs<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Op> {
+    let (n, m) = (old.len(), new.len());
+    // `lengths[i][j]` is the length of the LCS of `old[i..]` and `new[j..]`.
+    let mut lengths = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut ops = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if old[i] == new[j] {
+            ops.push(Op::Same(i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            ops.push(Op::Removed(i));
+            i += 1;
+        } else {
+            ops.push(Op::Added(j));
+            j += 1;
+        }
+    }
+    ops.extend((i..n).map(Op::Removed));
+    ops.extend((j..m).map(Op::Added));
+    ops
+}
+
+/// The value with its whitespace normalized, so that two values which only differ in their
+/// formatting compare equal.
+fn canonical(value: &Value, data: &str) -> String {
+    let open = match value.open {
+        Some(open) => open,
+        None => return collapse_whitespace(&data[value.start..value.end]),
+    };
+    let elements = value
+        .elements
+        .iter()
+        .map(|e| canonical_element(e, data))
+        .collect::<Vec<_>>();
+    format!(
+        "{}{}{}{}",
+        label(value, data).unwrap_or_default(),
+        open,
+        elements.join(", "),
+        closing(open)
+    )
+}
+
+fn canonical_element((key, value): &(Option<String>, Value), data: &str) -> String {
+    match key {
+        Some(key) => format!("{}: {}", key, canonical(value, data)),
+        None => canonical(value, data),
+    }
+}
+
+fn label(value: &Value, data: &str) -> Option<String> {
+    value
+        .label
+        .map(|(start, end)| collapse_whitespace(&data[start..end]))
+}
+
+/// The value on a single line, cut short if it's long.
+fn summary(value: &Value, data: &str) -> String {
+    let text = collapse_whitespace(&data[value.start..value.end]);
+    match text.char_indices().nth(SUMMARY_WIDTH) {
+        Some((idx, _)) => format!("{}…", &text[..idx]),
+        None => text,
+    }
+}
+
+fn collapse_whitespace(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn closing(open: char) -> char {
+    match open {
+        '(' => ')',
+        '[' => ']',
+        '{' => '}',
+        '<' => '>',
+        c => c,
+    }
+}
@@ -0,0 +1,118 @@
+//! Structured payloads embedded in free text, as in `Got response: {"ok": true} after 12ms`. Only
+//! the payloads are formatted, and the prose around them is copied as it is.
+
+use crate::{ParseNode, R};
+
+/// Finds the outermost containers under `node` which hold more than a single plain value, so
+/// that things like `(ok)` or `[INFO]` in the prose are left alone. A label directly in front of
+/// a container, as in `Some(1)` or `Foo { x: 1 }`, is included in its span.
+pub(crate) fn find_payloads(node: &dyn ParseNode<'_>, data: &[u8], out: &mut Vec<(usize, usize)>) {
+    if node.kind() == "container" {
+        if is_structured(node, data) {
+            out.push((label_start(data, node.start_byte()), node.end_byte()));
+        }
+        return;
+    }
+    let mut cursor = node.walk();
+    if cursor.goto_first_child() {
+        loop {
+            find_payloads(&*cursor.node(), data, out);
+            if !cursor.goto_next_sibling() {
+                break;
+            }
+        }
+    }
+}
+
+/// Whether a container has any commas, key-value separators or nested containers in it.
+fn is_structured(node: &dyn ParseNode<'_>, data: &[u8]) -> bool {
+    let mut cursor = node.walk();
+    if !cursor.goto_first_child() {
+        return false;
+    }
+    loop {
+        let child = cursor.node();
+        let is_open_or_close = matches!(cursor.field_name().as_deref(), Some("open" | "close"));
+        let structured = match child.kind().as_str() {
+            "container" | "," => true,
+            "symbol" | "conflicting_symbol" => {
+                matches!(&*child.utf8_text(data), ":" | "=" | "=>")
+            }
+            _ => !is_open_or_close && is_structured(&*child, data),
+        };
+        if structured {
+            return true;
+        }
+        if !cursor.goto_next_sibling() {
+            return false;
+        }
+    }
+}
+
+/// Where the label in front of the container starting at `start` begins: either a word attached
+/// to the bracket, or a capitalized word a space away from it, as in Rust's `Foo { x: 1 }`.
+pub(crate) fn label_start(data: &[u8], start: usize) -> usize {
+    let is_word = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+    let word_start = |end: usize| {
+        let mut pos = end;
+        while pos > 0 && is_word(data[pos - 1]) {
+            pos -= 1;
+        }
+        pos
+    };
+    let attached = word_start(start);
+    if attached != start {
+        return attached;
+    }
+    if start >= 2 && data[start - 1] == b' ' && data[start] == b'{' {
+        let spaced = word_start(start - 1);
+        if spaced < start - 1 && data[spaced].is_ascii_uppercase() {
+            return spaced;
+        }
+    }
+    start
+}
+
+/// Lays out the prose in `data` around its formatted payloads. Payloads which were split over
+/// several lines get lines of their own, while the rest stay where they were.
+pub(crate) fn format(data: &str, payloads: Vec<((usize, usize), Vec<R>)>, out: &mut Vec<R>) {
+    let mut pos = 0;
+    // Whether the last thing written was a payload on lines of its own.
+    let mut after_block = false;
+    for ((start, end), items) in payloads {
+        let mut prose = &data[pos..start];
+        if after_block || pos == 0 {
+            prose = prose.trim_start();
+        }
+        let is_block = items.iter().any(R::breaks_line);
+        if is_block {
+            prose = prose.trim_end();
+        }
+        push_prose(prose, out);
+        if (is_block || after_block) && !out.iter().all(R::is_blank) {
+            out.push(R::Newline);
+        }
+        out.extend(items);
+        after_block = is_block;
+        pos = end;
+    }
+    let mut prose = &data[pos..];
+    if after_block {
+        prose = prose.trim_start();
+        if !prose.trim_end().is_empty() {
+            out.push(R::Newline);
+        }
+    }
+    push_prose(prose.trim_end(), out);
+}
+
+fn push_prose(prose: &str, out: &mut Vec<R>) {
+    for (idx, line) in prose.split('\n').enumerate() {
+        if idx != 0 {
+            out.push(R::Newline);
+        }
+        if !line.is_empty() {
+            out.push(R::String(line.to_string()));
+        }
+    }
+}
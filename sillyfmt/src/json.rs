@@ -0,0 +1,144 @@
+//! Conversion of the parse tree to JSON. Containers whose elements are all `key: value` become
+//! objects and the rest become arrays, while anything else is copied as a string unless it looks
+//! like a number, boolean or null.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::query::{unquote, Value};
+use crate::verbatim::unescape;
+use crate::R;
+
+lazy_static! {
+    static ref NUMBER: Regex = Regex::new(r"^-?(0|[1-9]\d*)(\.\d+)?([eE][+-]?\d+)?$").unwrap();
+}
+
+/// The key which holds the label of a container like `Foo { x: 1 }`.
+const TYPE_KEY: &str = "__type";
+/// The key which holds the elements of a labeled container which has no keys of its own, e.g.
+/// `Some(1)`.
+const VALUES_KEY: &str = "__values";
+
+pub(crate) fn format(value: &Value, data: &str, out: &mut Vec<R>) {
+    format_value(value.unwrap_top_level(), data, out);
+}
+
+fn format_value(value: &Value, data: &str, out: &mut Vec<R>) {
+    if value.open.is_none() && value.elements.is_empty() {
+        out.push(R::String(scalar(data[value.start..value.end].trim())));
+        return;
+    }
+
+    let label = value.label.map(|(start, end)| data[start..end].trim());
+    let keyed = value
+        .elements
+        .iter()
+        .filter(|(key, _)| key.is_some())
+        .count();
+    if let Some(label) = label {
+        let mut entries = vec![(TYPE_KEY, Entry::String(label))];
+        if keyed == value.elements.len() && keyed != 0 {
+            entries.extend(fields(value));
+        } else if !value.elements.is_empty() {
+            entries.push((VALUES_KEY, Entry::Array(value)));
+        }
+        format_object(&entries, data, out);
+    } else if keyed == value.elements.len() && (keyed != 0 || value.open == Some('{')) {
+        format_object(&fields(value).collect::<Vec<_>>(), data, out);
+    } else {
+        format_array(value, data, out);
+    }
+}
+
+/// What goes on the right-hand side of an object entry.
+enum Entry<'a> {
+    String(&'a str),
+    Value(&'a Value),
+    /// The elements of a value, as an array.
+    Array(&'a Value),
+}
+
+fn fields(value: &Value) -> impl Iterator<Item = (&str, Entry<'_>)> {
+    value
+        .elements
+        .iter()
+        .filter_map(|(key, value)| Some((key.as_deref()?, Entry::Value(value))))
+}
+
+fn format_object(entries: &[(&str, Entry<'_>)], data: &str, out: &mut Vec<R>) {
+    if entries.is_empty() {
+        out.push(R::String("{}".to_string()));
+        return;
+    }
+    out.push(R::Char('{'));
+    out.push(R::Indent);
+    for (idx, (key, entry)) in entries.iter().enumerate() {
+        if idx != 0 {
+            out.push(R::Char(','));
+        }
+        out.push(R::Newline);
+        out.push(R::String(format!("{}: ", quote(key))));
+        match entry {
+            Entry::String(s) => out.push(R::String(quote(s))),
+            Entry::Value(value) => format_value(value, data, out),
+            Entry::Array(value) => format_array(value, data, out),
+        }
+    }
+    out.push(R::Unindent);
+    out.push(R::Newline);
+    out.push(R::Char('}'));
+}
+
+/// Writes the elements of `value` as an array. Any keyed elements among them become objects with
+/// a single entry.
+fn format_array(value: &Value, data: &str, out: &mut Vec<R>) {
+    if value.elements.is_empty() {
+        out.push(R::String("[]".to_string()));
+        return;
+    }
+    out.push(R::Char('['));
+    out.push(R::Indent);
+    for (idx, (key, element)) in value.elements.iter().enumerate() {
+        if idx != 0 {
+            out.push(R::Char(','));
+        }
+        out.push(R::Newline);
+        match key {
+            Some(key) => format_object(&[(key, Entry::Value(element))], data, out),
+            None => format_value(element, data, out),
+        }
+    }
+    out.push(R::Unindent);
+    out.push(R::Newline);
+    out.push(R::Char(']'));
+}
+
+/// Converts a leaf value to JSON, guessing at its type.
+fn scalar(text: &str) -> String {
+    match text {
+        "" | "null" | "None" | "nil" | "NULL" => "null".to_string(),
+        "true" | "True" => "true".to_string(),
+        "false" | "False" => "false".to_string(),
+        _ if NUMBER.is_match(text) => text.to_string(),
+        _ if unquote(text).len() != text.len() => quote(&unescape(unquote(text))),
+        _ => quote(text),
+    }
+}
+
+fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -1,7 +1,46 @@
+//! A formatter for text which looks vaguely like code.
+//!
+//! Input is parsed with the tree-sitter grammar in `tree-sitter-sillyfmt`, which only knows about
+//! brackets, commas, a handful of operators and everything else as `text`. That grammar is kept
+//! deliberately small and forgiving, and everything which depends on the options or needs to see
+//! whole tokens is handled around it instead:
+//!
+//! - The `verbatim` pre-scan finds URLs, locations, comments, string literals and blobs, and masks
+//!   the brackets and separators inside them before parsing, so that each one ends up in a single
+//!   `text` node and is copied through as it is.
+//! - Markup, logfmt records and stack traces aren't nested expressions at all, so `markup`,
+//!   `logfmt` and `stacktrace` recognize and lay them out without a parse tree.
+//! - Statements, calls and SQL clauses are picked out of the formatted items by the container
+//!   layout in this module and in `sql`.
+//!
+//! Most of these depend on the [`Options`]: which comment markers apply depends on the
+//! [`Dialect`], and encoded strings are only picked out when asked for. A generated parser can't
+//! switch its tokens at runtime, so they'd need a parser per combination. Keeping them out of the
+//! grammar also means the native and wasm builds share the same `parser.c` without having to
+//! regenerate it.
+
+use std::cell::Cell;
 use std::fmt::Debug;
-use std::io::{BufRead, BufReader, Read, Result, Write};
+use std::io::{self, BufRead, BufReader, Read, Result, Write};
 use std::mem;
 
+mod blob;
+mod diff;
+mod embedded;
+mod json;
+mod logfmt;
+mod logprefix;
+mod markup;
+mod options;
+mod query;
+mod sql;
+mod stacktrace;
+mod verbatim;
+
+pub use options::{BracketSpacing, Dialect, LogPrefix, Options, Spacing, TrailingComma};
+pub use query::Query;
+use verbatim::{SpanKind, VerbatimSpans};
+
 pub trait ParseTree {
     fn root_node(&self) -> Box<dyn ParseNode<'_> + '_>;
     fn debug_tree(&self) -> String;
@@ -26,6 +65,7 @@ pub trait ParseNode<'a> {
 pub fn silly_format(
     reader: impl Read,
     writer: impl Write,
+    options: &Options,
     format_on_newline: bool,
     print_debug: Option<impl Write>,
     parser: impl Fn(String) -> (Box<dyn ParseTree>, String),
@@ -34,6 +74,7 @@ pub fn silly_format(
     silly_format_iter(
         &mut reader.lines(),
         writer,
+        options,
         format_on_newline,
         print_debug,
         parser,
@@ -43,6 +84,7 @@ pub fn silly_format(
 pub fn silly_format_iter(
     reader: &mut impl Iterator<Item = Result<String>>,
     mut writer: impl Write,
+    options: &Options,
     format_on_newline: bool,
     mut print_debug: Option<impl Write>,
     parser: impl Fn(String) -> (Box<dyn ParseTree>, String),
@@ -56,7 +98,8 @@ pub fn silly_format_iter(
                 if !data.is_empty() {
                     do_format(
                         &mut writer,
-                        mem::replace(&mut data, String::new()),
+                        options,
+                        mem::take(&mut data),
                         print_debug.as_mut(),
                         &parser,
                     )?;
@@ -66,31 +109,86 @@ pub fn silly_format_iter(
         };
         if !line.is_empty() {
             data.push_str(&line);
-            data.push('\n');
+            // Some line readers (e.g. rustyline, when not attached to a terminal) keep the
+            // line terminator.
+            if !line.ends_with('\n') {
+                data.push('\n');
+            }
         }
 
         if line.is_empty() || format_on_newline {
             do_format(
                 &mut writer,
-                mem::replace(&mut data, String::new()),
+                options,
+                mem::take(&mut data),
                 print_debug.as_mut(),
                 &parser,
             )?;
         }
     }
     if !data.is_empty() {
-        do_format(&mut writer, data, print_debug.as_mut(), &parser)?;
+        do_format(&mut writer, options, data, print_debug.as_mut(), &parser)?;
     }
     Ok(())
 }
 
-fn format_parse_cursor<'a, 'b, DW: Write>(
-    mut cursor: Box<dyn ParseCursor<'a> + 'a>,
+/// Whether `data` opens at least one bracket and closes all of those it opens, not counting any
+/// in comments or string literals. Interactive front ends use this to tell when a container has
+/// been entered in full.
+pub fn brackets_closed(data: &str, options: &Options) -> bool {
+    let verbatim = VerbatimSpans::find(data, options);
+    let mut spans = verbatim.overlapping(0, data.len()).peekable();
+    let bytes = data.as_bytes();
+    let mut depth = 0usize;
+    let mut opened = false;
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if let Some(span) = spans.next_if(|span| span.start == pos) {
+            pos = span.end;
+            continue;
+        }
+        match bytes[pos] {
+            b'"' => {
+                // Plain string literals end at their closing quote, or at the end of the line if
+                // there isn't one.
+                pos += 1;
+                while pos < bytes.len() && !matches!(bytes[pos], b'"' | b'\n') {
+                    pos += if bytes[pos] == b'\\' { 2 } else { 1 };
+                }
+            }
+            b'(' | b'[' | b'{' => {
+                depth += 1;
+                opened = true;
+            }
+            b')' | b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+        pos += 1;
+    }
+    opened && depth == 0
+}
+
+type Parser<'a> = dyn Fn(String) -> (Box<dyn ParseTree>, String) + 'a;
+
+/// State shared by the whole formatting pass over a single input.
+struct Context<'b> {
     data: &'b [u8],
+    options: &'b Options,
+    verbatim: VerbatimSpans,
+    /// For formatting the contents of encoded strings.
+    parser: &'b Parser<'b>,
+    /// How many containers deep the node being formatted is.
+    depth: Cell<usize>,
+}
+
+fn format_parse_cursor<'a, DW: Write>(
+    mut cursor: Box<dyn ParseCursor<'a> + 'a>,
+    ctx: &Context<'_>,
     from: usize,
     to: usize,
     mut print_debug: Option<DW>,
 ) -> (Vec<R>, Option<DW>) {
+    let data = ctx.data;
     let mut out = Vec::new();
     let node = cursor.node();
     if let Ok(p) = std::str::from_utf8(&data[from..node.start_byte()]) {
@@ -125,7 +223,7 @@ fn format_parse_cursor<'a, 'b, DW: Write>(
                     } else {
                         let (mut res, print_debug_) = format_parse_cursor(
                             inner_node.walk(),
-                            data,
+                            ctx,
                             inner_node.start_byte(),
                             inner_node.end_byte(),
                             print_debug,
@@ -147,14 +245,16 @@ fn format_parse_cursor<'a, 'b, DW: Write>(
                 }
             }
 
-            format_seq(formatted, &mut out);
+            format_seq(formatted, ctx.options.inline_width(), &mut out);
         }
-        "text" | "time" => out.extend(minimize_whitespace(&node.utf8_text(data))),
+        "text" | "time" => out.extend(format_text(ctx, node.start_byte(), node.end_byte())),
         "," => out.push(R::Delimiter(',', true)),
         "container" => {
             let mut formatted_children = vec![];
             let mut open = ' ';
             let mut close = ' ';
+            let depth = ctx.depth.get() + 1;
+            ctx.depth.set(depth);
             if cursor.goto_first_child() {
                 // Try to format all the children.
                 let mut seq = node.start_byte();
@@ -174,7 +274,7 @@ fn format_parse_cursor<'a, 'b, DW: Write>(
                         }
                         _ => {
                             let (res, print_debug_) =
-                                format_parse_cursor(node.walk(), data, seq, end_byte, print_debug);
+                                format_parse_cursor(node.walk(), ctx, seq, end_byte, print_debug);
                             formatted_children.push(res);
                             print_debug = print_debug_;
                         }
@@ -186,30 +286,130 @@ fn format_parse_cursor<'a, 'b, DW: Write>(
                 }
             }
 
+            ctx.depth.set(depth - 1);
+
             let mut e = vec![];
-            format_seq(formatted_children, &mut e);
+            format_seq(formatted_children, ctx.options.inline_width(), &mut e);
+            let mut e = format_statements(e, open == '{');
+            if ctx.options.dialect == Dialect::Sql {
+                e = sql::format_clauses(e, ctx.options.uppercase_keywords);
+            }
+            if ctx.options.drop_empty_elements {
+                e = drop_empty_elements(e);
+            }
+            if ctx.options.collapse_repeats || ctx.options.max_elements.is_some() {
+                e = summarize_elements(e, ctx.options);
+            }
+            let has_commas = count_commas(&e) > 0;
+            if ctx.options.trailing_commas != TrailingComma::Preserve && count_commas(&e) > 1 {
+                // A lone trailing comma can be significant, as in `(x,)`, so it's left alone.
+                strip_trailing_comma(&mut e);
+            }
+            if trailing_comma(&e).is_some() {
+                // Otherwise a lone trailing comma is followed by a stray space, as in `g(x, )`.
+                while e.last().is_some_and(R::is_blank) {
+                    e.pop();
+                }
+            }
+            // A call is kept on one line whenever the whole thing fits, no matter how the
+            // arguments would have been split on their own.
+            let callee = callee(data, node.start_byte());
+            // SQL keywords aren't callees, and are kept apart from their brackets, as in
+            // `IN (SELECT ...)`.
+            let after_keyword = ctx.options.dialect == Dialect::Sql
+                && sql::is_keyword(&String::from_utf8_lossy(callee));
+            let callee_len = if after_keyword { 0 } else { callee.len() };
+            let is_call = open == '(' && callee_len > 0;
+            // Roughly where the call starts, assuming the containers around it are exploded.
+            let indent = 2 * (depth - 1);
+            if is_call {
+                let joined = join_sequence(&e);
+                let joined_len = joined.iter().map(|it| it.len()).sum::<usize>();
+                if indent + callee_len + joined_len + 2 <= ctx.options.line_width()
+                    && joined.iter().all(|it| !it.breaks_line())
+                {
+                    e = joined;
+                }
+            }
             let e_len = e.iter().map(|it| it.len()).sum::<usize>();
-            if e_len < 5 && e.iter().all(|e| !e.is_newline()) {
+            let fits = if is_call {
+                indent + callee_len + e_len + 2 <= ctx.options.line_width()
+            } else {
+                e_len < ctx.options.inline_width()
+            };
+            let folded =
+                ctx.options.max_depth.is_some_and(|max| depth > max) && !e.iter().all(R::is_layout);
+            let elements = split_top_level(e.clone(), |it| matches!(it, R::Delimiter(',', _)));
+            let elements = elements
+                .iter()
+                .map(|element| trim(element, R::is_layout))
+                .collect::<Vec<_>>();
+            let byte_array = blob::format_byte_array(&elements, open, close, ctx.options);
+            if after_keyword {
+                out.push(R::Space);
+            }
+            if folded {
+                let count = split_top_level(e, |it| matches!(it, R::Delimiter(',', _)))
+                    .iter()
+                    .filter(|element| !trim(element, R::is_layout).is_empty())
+                    .count();
                 out.push(R::Char(open));
-                out.extend(e);
+                out.push(R::String(format!(
+                    "… {} {}",
+                    count,
+                    if count == 1 { "item" } else { "items" }
+                )));
                 out.push(R::Char(close));
-            } else if e_len < 32 && e.iter().all(|e| !e.is_newline()) {
+            } else if let Some(byte_array) = byte_array {
+                out.extend(byte_array);
+            } else if fits && e.iter().all(|e| !e.breaks_line()) {
+                // Padding replaces whatever whitespace the input had inside the brackets.
+                let e = trim(&e, R::is_blank).to_vec();
+                let padded = match ctx.options.bracket_spacing.for_open(open) {
+                    Spacing::Auto => e_len >= 5 && !after_keyword,
+                    Spacing::Always => !e.iter().all(R::is_blank),
+                    Spacing::Never => false,
+                };
                 out.push(R::Char(open));
-                out.push(R::Space);
+                if padded {
+                    out.push(R::Space);
+                }
                 out.extend(e);
-                out.push(R::Space);
+                if padded {
+                    out.push(R::Space);
+                }
                 out.push(R::Char(close));
             } else {
                 out.push(R::Char(open));
                 out.push(R::Indent);
-                out.push(R::Newline);
-                loop {
-                    match e.last() {
-                        Some(R::Newline) | Some(R::Space) => {
-                            e.pop();
-                        }
-                        _ => break,
-                    }
+                let leading = e
+                    .iter()
+                    .take_while(|it| matches!(it, R::Newline | R::Space))
+                    .count();
+                e.drain(..leading);
+                while let Some(R::Newline) | Some(R::Space) = e.last() {
+                    e.pop();
+                }
+                if let Some(table) = format_table(&e, ctx.options.line_width()) {
+                    e = table;
+                } else if ctx.options.align_values {
+                    e = align_entries(e);
+                }
+                if ctx.options.trailing_commas == TrailingComma::Vertical
+                    && (has_commas || is_call)
+                    && trailing_comma(&e).is_none()
+                {
+                    // Before any comment at the end, which would otherwise swallow it.
+                    let end = e
+                        .iter()
+                        .rposition(|it| !it.is_blank() && !matches!(it, R::LineComment(_)))
+                        .map_or(0, |idx| idx + 1);
+                    e.insert(end, R::Char(','));
+                }
+                // Keep a comment which follows the opening bracket on the same line.
+                match e.first() {
+                    Some(R::LineComment(_)) => out.push(R::Space),
+                    _ => out.push(R::Newline),
                 }
                 out.extend(e);
                 out.push(R::Unindent);
@@ -225,7 +425,7 @@ fn format_parse_cursor<'a, 'b, DW: Write>(
                     let node = cursor.node();
                     let (res, print_debug_) = format_parse_cursor(
                         node.walk(),
-                        data,
+                        ctx,
                         node.start_byte(),
                         node.end_byte(),
                         print_debug,
@@ -238,7 +438,7 @@ fn format_parse_cursor<'a, 'b, DW: Write>(
                 }
             }
 
-            format_seq(formatted, &mut out);
+            format_seq(formatted, ctx.options.inline_width(), &mut out);
         }
         _ if node.is_named() => {
             let mut formatted = vec![];
@@ -249,7 +449,7 @@ fn format_parse_cursor<'a, 'b, DW: Write>(
                     let node = cursor.node();
                     let end = node.end_byte();
                     let (res, print_debug_) =
-                        format_parse_cursor(node.walk(), data, seq, end, print_debug);
+                        format_parse_cursor(node.walk(), ctx, seq, end, print_debug);
                     formatted.push(res);
                     print_debug = print_debug_;
                     seq = end;
@@ -259,7 +459,7 @@ fn format_parse_cursor<'a, 'b, DW: Write>(
                 }
             }
 
-            format_seq(formatted, &mut out);
+            format_seq(formatted, ctx.options.inline_width(), &mut out);
         }
         _ => {
             out.extend(minimize_whitespace(&node.utf8_text(data)));
@@ -272,6 +472,127 @@ fn format_parse_cursor<'a, 'b, DW: Write>(
     (out, print_debug)
 }
 
+/// Formats `start..end` of the input as free text, copying any verbatim spans within it as-is
+/// and marking the `;`s which might separate statements.
+fn format_text(ctx: &Context<'_>, start: usize, end: usize) -> Vec<R> {
+    let text = |from: usize, to: usize| std::str::from_utf8(&ctx.data[from..to]).unwrap_or("");
+    let mut spans = ctx.verbatim.overlapping(start, end).peekable();
+    let mut semicolons = ctx.verbatim.semicolons(start, end).peekable();
+    if spans.peek().is_none() && semicolons.peek().is_none() {
+        return minimize_whitespace(text(start, end));
+    }
+
+    let mut out = vec![];
+    let mut pos = start;
+    loop {
+        let span_start = spans.peek().map(|span| span.start.max(start));
+        let (item_start, item_end, item) = match (span_start, semicolons.peek()) {
+            (_, Some(&semicolon)) if span_start.is_none_or(|s| semicolon < s) => {
+                semicolons.next();
+                (semicolon, semicolon + 1, vec![R::Delimiter(';', false)])
+            }
+            (Some(span_start), _) => {
+                let span = spans.next().unwrap();
+                let span_end = span.end.min(end);
+                let s = text(span_start, span_end);
+                let item = match span.kind {
+                    SpanKind::LineComment => vec![R::LineComment(s.trim_end().to_string())],
+                    SpanKind::String if ctx.options.expand_escapes => expand_escapes(s),
+                    SpanKind::EncodedString => format_encoded(ctx, s),
+                    SpanKind::Blob => vec![blob::format_string(s, ctx.options)],
+                    _ => vec![R::String(s.to_string())],
+                };
+                (span_start, span_end, item)
+            }
+            (None, _) => break,
+        };
+        out.extend(minimize_whitespace_between(
+            text(pos, item_start),
+            pos != start,
+            true,
+        ));
+        out.extend(item);
+        pos = item_end;
+    }
+    out.extend(minimize_whitespace_between(text(pos, end), true, false));
+    out
+}
+
+/// Written in front of the contents of an encoded string, so they can't be mistaken for the
+/// surrounding structure.
+const ENCODED_MARKER: &str = "(string)";
+
+/// Formats the container held in a string literal, marking where it came from.
+fn format_encoded(ctx: &Context<'_>, s: &str) -> Vec<R> {
+    let decoded = verbatim::unescape(&s[1..s.len() - 1]);
+    match format_tree(ctx.options, decoded.trim(), None::<io::Sink>, ctx.parser) {
+        Ok(items) => {
+            let mut out = vec![R::String(ENCODED_MARKER.to_string()), R::Space];
+            out.extend(items);
+            out
+        }
+        Err(_) => vec![R::String(s.to_string())],
+    }
+}
+
+/// Renders the escaped newlines in a string literal as actual line breaks, indenting the
+/// continuation lines.
+fn expand_escapes(s: &str) -> Vec<R> {
+    let mut out = vec![R::Indent];
+    let mut line = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some('n')) => {
+                chars.next();
+                out.push(R::String(mem::take(&mut line)));
+                out.push(R::Newline);
+            }
+            ('\\', Some(&escaped)) => {
+                chars.next();
+                line.push(c);
+                line.push(escaped);
+            }
+            _ => line.push(c),
+        }
+    }
+    out.push(R::String(line));
+    out.push(R::Unindent);
+    out
+}
+
+/// Like `minimize_whitespace`, but optionally keeps (minimized) leading and trailing whitespace,
+/// which is significant when the text is adjacent to a verbatim span.
+fn minimize_whitespace_between(s: &'_ str, keep_leading: bool, keep_trailing: bool) -> Vec<R> {
+    let whitespace = |w: &str| {
+        if w.contains('\n') {
+            R::Newline
+        } else {
+            R::Space
+        }
+    };
+    let trimmed = s.trim();
+    if trimmed.is_empty() {
+        return if !s.is_empty() && keep_leading && keep_trailing {
+            vec![whitespace(s)]
+        } else {
+            vec![]
+        };
+    }
+
+    let mut out = vec![];
+    let leading = &s[..s.len() - s.trim_start().len()];
+    if keep_leading && !leading.is_empty() {
+        out.push(whitespace(leading));
+    }
+    out.extend(minimize_whitespace(trimmed));
+    let trailing = &s[s.trim_end().len()..];
+    if keep_trailing && !trailing.is_empty() {
+        out.push(whitespace(trailing));
+    }
+    out
+}
+
 fn minimize_whitespace(s: &'_ str) -> Vec<R> {
     match s.len() {
         0 => vec![],
@@ -288,7 +609,7 @@ fn minimize_whitespace(s: &'_ str) -> Vec<R> {
 
             for c in s.trim().chars() {
                 if c.is_whitespace() {
-                    out.push(R::String(mem::replace(&mut s_out, String::new())));
+                    out.push(R::String(mem::take(&mut s_out)));
                     s_whitespace = Some(match (s_whitespace, c) {
                         (Some(_), c) if c == '\n' => c,
                         (Some(w), _) => w,
@@ -319,7 +640,61 @@ fn minimize_whitespace(s: &'_ str) -> Vec<R> {
     }
 }
 
-fn format_seq(formatted: Vec<Vec<R>>, out: &mut Vec<R>) {
+/// Puts each `;`-terminated statement on its own line if `split` is set. Either way, the
+/// separators are consumed, so that enclosing containers leave their statements alone.
+fn format_statements(items: Vec<R>, split: bool) -> Vec<R> {
+    let mut out = Vec::with_capacity(items.len());
+    let mut items = items.into_iter().peekable();
+    while let Some(item) = items.next() {
+        match item {
+            R::Delimiter(';', _) if split => {
+                out.push(R::Char(';'));
+                while items.peek().is_some_and(R::is_blank) {
+                    items.next();
+                }
+                match items.peek() {
+                    None => (),
+                    Some(R::LineComment(_)) => out.push(R::Space),
+                    Some(_) => out.push(R::Newline),
+                }
+            }
+            R::Delimiter(';', _) => out.push(R::Char(';')),
+            _ => out.push(item),
+        }
+    }
+    out
+}
+
+/// Outside of `{}` blocks, a `;` could just as well be punctuation, as in `connection reset;
+/// retrying`. Only lines which end with a `;` are taken to be statements, and the `;`s on any
+/// other line are kept as they are.
+fn keep_prose_semicolons(mut items: Vec<R>) -> Vec<R> {
+    let mut line_start = 0;
+    while line_start < items.len() {
+        let line_end = items[line_start..]
+            .iter()
+            .position(|it| matches!(it, R::Newline))
+            .map_or(items.len(), |idx| line_start + idx);
+        let line = &mut items[line_start..line_end];
+        let terminated = line
+            .iter()
+            .rfind(|it| !it.is_layout() && !matches!(it, R::LineComment(_)))
+            .is_some_and(|it| matches!(it, R::Delimiter(';', _)));
+        if !terminated {
+            for item in line.iter_mut() {
+                if let R::Delimiter(';', _) = item {
+                    *item = R::Char(';');
+                }
+            }
+        }
+        line_start = line_end + 1;
+    }
+    items
+}
+
+/// Joins a sequence of formatted elements, breaking the line after each delimiter unless they're
+/// shorter than `inline_width` altogether.
+fn format_seq(formatted: Vec<Vec<R>>, inline_width: usize, out: &mut Vec<R>) {
     let (has_breakable, sum) = formatted
         .iter()
         .fold((false, 0), |(mut breakable, mut sum), it| {
@@ -329,62 +704,421 @@ fn format_seq(formatted: Vec<Vec<R>>, out: &mut Vec<R>) {
             }
             (breakable, sum)
         });
-    if !has_breakable || sum < 32 {
+    if !has_breakable || sum < inline_width {
         let last = if formatted.is_empty() {
             0
         } else {
             formatted.len() - 1
         };
         // It all fits in one line!
-        out.extend(
-            formatted
-                .into_iter()
-                .enumerate()
-                .map(|(idx, mut e)| {
-                    if e.len() == 1 && e.iter().any(|it| it.is_breakable_delimiter()) && idx != last
-                    {
-                        e.push(R::Space);
-                    }
-                    e
-                })
-                .flatten(),
-        );
+        out.extend(formatted.into_iter().enumerate().flat_map(|(idx, mut e)| {
+            if e.len() == 1 && e.iter().any(|it| it.is_breakable_delimiter()) && idx != last {
+                e.push(R::Space);
+            }
+            e
+        }));
     } else {
         // Add newlines after delimiters
-        out.extend(
-            formatted
-                .into_iter()
-                .map(|mut e| {
-                    if e.len() == 1 && e.iter().any(|it| it.is_breakable_delimiter()) {
-                        e.push(R::Newline);
+        let mut formatted = formatted.into_iter().peekable();
+        while let Some(mut e) = formatted.next() {
+            if e.len() == 1 && e.iter().any(|it| it.is_breakable_delimiter()) {
+                // Keep a trailing comment on the same line as the delimiter it follows.
+                match formatted.peek().and_then(|next| next.first()) {
+                    Some(R::LineComment(_)) => e.push(R::Space),
+                    _ => e.push(R::Newline),
+                }
+            }
+            out.extend(e);
+        }
+    }
+}
+
+/// Runs of identical elements at least this long are collapsed by `collapse_repeats`.
+const MIN_REPEATS: usize = 4;
+
+/// Entries in an exploded container are padded by at most this much to line up their values.
+const ALIGN_MAX_PADDING: usize = 16;
+
+/// Pads the entries of an exploded container so that the values which follow their `:`, `=` or
+/// `=>` line up. Entries whose keys are too long to pad the rest up to are left alone.
+fn align_entries(items: Vec<R>) -> Vec<R> {
+    let lines = split_top_level(items, |item| matches!(item, R::Newline));
+    let separators = lines.iter().map(|l| find_separator(l)).collect::<Vec<_>>();
+    let mut widths: Vec<(&str, usize)> = vec![];
+    for &(kind, _, key_len) in separators.iter().flatten() {
+        let shortest = separators
+            .iter()
+            .flatten()
+            .filter(|s| s.0 == kind)
+            .map(|s| s.2)
+            .min()
+            .unwrap_or(key_len);
+        if key_len <= shortest + ALIGN_MAX_PADDING {
+            match widths.iter_mut().find(|w| w.0 == kind) {
+                Some(w) => w.1 = w.1.max(key_len),
+                None => widths.push((kind, key_len)),
+            }
+        }
+    }
+
+    let mut out = vec![];
+    for (idx, (mut line, separator)) in lines.into_iter().zip(separators).enumerate() {
+        if idx != 0 {
+            out.push(R::Newline);
+        }
+        if let Some((kind, pos, key_len)) = separator {
+            let width = widths.iter().find(|w| w.0 == kind).map_or(0, |w| w.1);
+            if key_len < width {
+                line.insert(pos, R::String(" ".repeat(width - key_len)));
+            }
+        }
+        out.extend(line);
+    }
+    out
+}
+
+/// The callee if the container starting at `start` is called like a function, as in `foo(a)` or
+/// `self.foo (a)`, or an empty slice otherwise.
+fn callee(data: &[u8], start: usize) -> &[u8] {
+    let callee = &data[..start];
+    let callee = &callee[..callee.len() - callee.iter().rev().take_while(|&&c| c == b' ').count()];
+    if !callee
+        .last()
+        .is_some_and(|&c| c.is_ascii_alphanumeric() || c == b'_' || c == b'!')
+    {
+        return &[];
+    }
+    let len = callee
+        .iter()
+        .rev()
+        .take_while(|&&c| !c.is_ascii_whitespace() && !b",;=()[]{}".contains(&c))
+        .count();
+    &callee[callee.len() - len..]
+}
+
+/// Undoes the line breaks which `format_seq` put after the commas in `items`.
+fn join_sequence(items: &[R]) -> Vec<R> {
+    let mut out = Vec::with_capacity(items.len());
+    let mut depth = 0usize;
+    let mut items = items.iter().peekable();
+    while let Some(item) = items.next() {
+        match item {
+            R::Char('(' | '[' | '{' | '<') => depth += 1,
+            R::Char(')' | ']' | '}' | '>') => depth = depth.saturating_sub(1),
+            R::Delimiter(',', true) if depth == 0 => {
+                if let Some(R::Newline) = items.peek() {
+                    items.next();
+                    out.push(item.clone());
+                    out.push(R::Space);
+                    continue;
+                }
+            }
+            _ => (),
+        }
+        out.push(item.clone());
+    }
+    out
+}
+
+/// Shortens a container's elements as configured by `collapse_repeats` and `max_elements`, then
+/// lays them out again.
+fn summarize_elements(items: Vec<R>, options: &Options) -> Vec<R> {
+    let mut elements = split_top_level(items.clone(), |it| matches!(it, R::Delimiter(',', _)))
+        .into_iter()
+        .map(|element| trim(&element, R::is_layout).to_vec())
+        .collect::<Vec<_>>();
+    let trailing_comma = elements.len() > 1 && elements.last().unwrap().is_empty();
+    if trailing_comma {
+        elements.pop();
+    }
+
+    let mut summarized: Vec<Vec<R>> = vec![];
+    let mut elements = elements.into_iter().peekable();
+    while let Some(element) = elements.next() {
+        let mut count = 1;
+        if options.collapse_repeats {
+            while elements.next_if(|next| *next == element).is_some() {
+                count += 1;
+            }
+        }
+        if count >= MIN_REPEATS {
+            let mut element = element;
+            element.push(R::Space);
+            element.push(R::String(format!("× {}", count)));
+            summarized.push(element);
+        } else {
+            summarized.extend(std::iter::repeat_n(element, count));
+        }
+    }
+    let total = summarized.len();
+    if let Some(max) = options.max_elements.filter(|&max| total > max) {
+        summarized.truncate(max);
+        summarized.push(vec![R::String(format!("... {} more", total - max))]);
+    }
+    if summarized.len() + usize::from(trailing_comma) == count_commas(&items) + 1 {
+        // Nothing changed, so keep the original layout.
+        return items;
+    }
+
+    let mut formatted = vec![];
+    for (idx, element) in summarized.into_iter().enumerate() {
+        if idx != 0 {
+            formatted.push(vec![R::Delimiter(',', true)]);
+        }
+        formatted.push(element);
+    }
+    if trailing_comma {
+        formatted.push(vec![R::Delimiter(',', true)]);
+    }
+    let mut out = vec![];
+    format_seq(formatted, options.inline_width(), &mut out);
+    out
+}
+
+/// The number of commas in `items` which aren't inside a nested container.
+fn count_commas(items: &[R]) -> usize {
+    split_top_level(items.to_vec(), |it| matches!(it, R::Delimiter(',', _))).len() - 1
+}
+
+/// The index of the comma at the end of `items`, if there is one.
+fn trailing_comma(items: &[R]) -> Option<usize> {
+    items
+        .iter()
+        .rposition(|it| !it.is_blank() && !matches!(it, R::Indent | R::Unindent))
+        .filter(|&idx| matches!(items[idx], R::Delimiter(',', _)))
+}
+
+/// Removes the comma at the end of `items`, along with the whitespace around it.
+fn strip_trailing_comma(items: &mut Vec<R>) {
+    if let Some(idx) = trailing_comma(items) {
+        let start = items[..idx]
+            .iter()
+            .rposition(|it| !it.is_blank())
+            .map_or(0, |i| i + 1);
+        let tail = items.split_off(idx + 1);
+        items.truncate(start);
+        items.extend(tail.into_iter().filter(|it| !it.is_blank()));
+    }
+}
+
+/// Removes the commas which separate empty elements, like the second one in `a, , c`.
+fn drop_empty_elements(items: Vec<R>) -> Vec<R> {
+    let mut out: Vec<R> = Vec::with_capacity(items.len());
+    let mut depth = 0usize;
+    // Whether there has been nothing but whitespace since the start or the last comma.
+    let mut empty = true;
+    // Leading whitespace goes too, so that dropping a leading comma doesn't leave a gap.
+    let mut at_start = true;
+    for item in items {
+        match item {
+            _ if at_start && item.is_blank() => continue,
+            R::Char('(' | '[' | '{' | '<') => depth += 1,
+            R::Char(')' | ']' | '}' | '>') => depth = depth.saturating_sub(1),
+            R::Delimiter(',', _) if depth == 0 => {
+                if empty {
+                    while out.last().is_some_and(R::is_blank) {
+                        out.pop();
                     }
-                    e
+                    continue;
+                }
+                empty = true;
+                out.push(item);
+                continue;
+            }
+            _ => (),
+        }
+        if !item.is_blank() && !matches!(item, R::Indent | R::Unindent) {
+            empty = false;
+            at_start = false;
+        }
+        out.push(item);
+    }
+    out
+}
+
+/// Splits `items` at each item matching `at` which isn't inside a nested container, dropping the
+/// items split at.
+fn split_top_level(items: Vec<R>, at: impl Fn(&R) -> bool) -> Vec<Vec<R>> {
+    let mut parts = vec![vec![]];
+    let mut depth = 0usize;
+    for item in items {
+        match item {
+            R::Char('(' | '[' | '{' | '<') => depth += 1,
+            R::Char(')' | ']' | '}' | '>') => depth = depth.saturating_sub(1),
+            _ if depth == 0 && at(&item) => {
+                parts.push(vec![]);
+                continue;
+            }
+            _ => (),
+        }
+        parts.last_mut().unwrap().push(item);
+    }
+    parts
+}
+
+/// Lays out a container of records which all have the same keys as a table, one record per row
+/// with the columns padded to line up, e.g. `{ id: 0, name: "x" }`. Returns `None` if the
+/// elements aren't such records, or the rows wouldn't fit in `width` characters.
+fn format_table(items: &[R], width: usize) -> Option<Vec<R>> {
+    let mut records = split_top_level(items.to_vec(), |it| matches!(it, R::Delimiter(',', _)));
+    let trailing_comma = records.len() > 1 && records.last().unwrap().iter().all(R::is_layout);
+    if trailing_comma {
+        records.pop();
+    }
+    if records.len() < 2 {
+        return None;
+    }
+
+    let mut rows = vec![];
+    for record in records {
+        let record = trim(&record, R::is_layout);
+        let (open, close) = match (record.first(), record.last()) {
+            (Some(R::Char(open)), Some(R::Char(close))) if record.len() > 2 => (*open, *close),
+            _ => return None,
+        };
+        let mut cells = vec![];
+        for entry in split_top_level(record[1..record.len() - 1].to_vec(), |it| {
+            matches!(it, R::Delimiter(',', _))
+        }) {
+            let entry = trim(&entry, R::is_layout);
+            let (":", pos, _) = find_separator(entry)? else {
+                return None;
+            };
+            cells.push((render_line(&entry[..pos])?, render_line(&entry[pos..])?));
+        }
+        rows.push((open, cells, close));
+    }
+    let (open, first, close) = &rows[0];
+    // Columns are matched by position, so every row needs the same keys in the same order.
+    if rows.iter().any(|(o, cells, c)| {
+        o != open
+            || c != close
+            || cells.len() != first.len()
+            || cells.iter().zip(first).any(|(cell, f)| cell.0 != f.0)
+    }) {
+        return None;
+    }
+
+    let widths = (0..first.len())
+        .map(|col| {
+            rows.iter()
+                .map(|(_, cells, _)| {
+                    // Counted in chars, since that's what `{:width$}` pads to.
+                    cells[col].0.chars().count() + 1 + cells[col].1.trim().chars().count()
                 })
-                .flatten(),
-        );
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+    let mut out = vec![];
+    for (idx, (open, cells, close)) in rows.iter().enumerate() {
+        if idx != 0 {
+            out.push(R::Char(','));
+            out.push(R::Newline);
+        }
+        let mut row = format!("{} ", open);
+        for (col, (key, value)) in cells.iter().enumerate() {
+            let cell = format!("{} {}", key, value.trim());
+            if col + 1 == cells.len() {
+                row.push_str(&cell);
+            } else {
+                row.push_str(&format!(
+                    "{:width$} ",
+                    format!("{},", cell),
+                    width = widths[col] + 1
+                ));
+            }
+        }
+        row.push_str(&format!(" {}", close));
+        if row.chars().count() > width {
+            return None;
+        }
+        out.push(R::String(row));
+    }
+    if trailing_comma {
+        out.push(R::Char(','));
+    }
+    Some(out)
+}
+
+fn trim(items: &[R], f: impl Fn(&R) -> bool) -> &[R] {
+    let start = items.iter().position(|it| !f(it)).unwrap_or(items.len());
+    let end = items
+        .iter()
+        .rposition(|it| !f(it))
+        .map_or(start, |end| end + 1);
+    &items[start..end]
+}
+
+/// Renders items which must all fit on one line, or returns `None` if they don't.
+fn render_line(items: &[R]) -> Option<String> {
+    let mut s = String::new();
+    for item in items {
+        match item {
+            R::String(text) => s.push_str(text),
+            R::Char(c) | R::Delimiter(c, _) => s.push(*c),
+            R::Space => s.push(' '),
+            R::Indent | R::Unindent => (),
+            R::Newline | R::LineComment(_) => return None,
+        }
+    }
+    Some(s)
+}
+
+/// Finds the separator between an entry's key and its value, returning its kind, where padding
+/// should go to line the values up, and the length of everything before that.
+fn find_separator(line: &[R]) -> Option<(&'static str, usize, usize)> {
+    let mut depth = 0usize;
+    let mut len = 0;
+    for (idx, item) in line.iter().enumerate() {
+        match item {
+            R::Char('(' | '[' | '{' | '<') => depth += 1,
+            R::Char(')' | ']' | '}' | '>') => depth = depth.saturating_sub(1),
+            // Values after a `:` are padded, e.g. `a:   1`, but for `=` and `=>` it's the
+            // separator itself, e.g. `a   = 1`.
+            R::Delimiter(':', _) if depth == 0 && idx != 0 => {
+                return Some((":", idx + 1, len + 1));
+            }
+            R::Delimiter('=', _) if depth == 0 && idx != 0 => return Some(("=", idx, len)),
+            R::String(s) if depth == 0 && idx != 0 && s == "=>" => return Some(("=>", idx, len)),
+            R::LineComment(_) | R::Newline => return None,
+            _ => (),
+        }
+        len += item.len();
     }
+    None
 }
 
 pub fn do_format(
     writer: impl Write,
+    options: &Options,
     data: String,
     mut print_debug: Option<impl Write>,
     parser: impl Fn(String) -> (Box<dyn ParseTree>, String),
 ) -> Result<()> {
-    let (tree, data) = parser(data);
-    if let Some(debug) = print_debug.as_mut() {
-        writeln!(debug, "==============================")?;
-        writeln!(debug, "{}", tree.debug_tree())?;
-    }
-
-    let data_as_bytes = data.as_bytes();
-    let (items, mut print_debug) = format_parse_cursor(
-        tree.root_node().walk(),
-        data_as_bytes,
-        0,
-        data_as_bytes.len(),
-        print_debug,
-    );
+    let items = match &options.log_prefix {
+        Some(prefix) => {
+            let records = logprefix::split(prefix, &data);
+            if let Some(debug) = print_debug.as_mut() {
+                writeln!(debug, "==============================")?;
+                writeln!(debug, "{:?}", records)?;
+            }
+            let mut items = vec![];
+            for (idx, (prefix, payload)) in records.iter().enumerate() {
+                if idx != 0 {
+                    items.push(R::Newline);
+                }
+                let payload = if payload.trim().is_empty() {
+                    vec![]
+                } else {
+                    format_input(options, payload, print_debug.as_mut(), &parser)?
+                };
+                logprefix::format(*prefix, payload, &mut items);
+            }
+            items
+        }
+        None => format_input(options, &data, print_debug.as_mut(), &parser)?,
+    };
     if let Some(mut debug) = print_debug.as_mut() {
         writeln!(debug, "------------------------------")?;
         write_output(items.iter(), &mut debug)?;
@@ -396,10 +1130,352 @@ pub fn do_format(
     Ok(())
 }
 
+/// Formats a single input, picking the formatter which suits it.
+fn format_input(
+    options: &Options,
+    data: &str,
+    mut print_debug: Option<impl Write>,
+    parser: impl Fn(String) -> (Box<dyn ParseTree>, String),
+) -> Result<Vec<R>> {
+    Ok(if options.select.is_some() || options.to_json {
+        let verbatim = VerbatimSpans::find(data, options);
+        let (tree, _) = parser(verbatim.mask(data));
+        let root = query::Value::from_tree(&*tree.root_node(), data.as_bytes());
+        if let Some(debug) = print_debug.as_mut() {
+            writeln!(debug, "==============================")?;
+            writeln!(debug, "{:?}", root)?;
+        }
+        let matches = match &options.select {
+            Some(query) => root.select(query),
+            None => vec![&root],
+        };
+        // Each match is formatted on its own.
+        let options = Options {
+            select: None,
+            ..options.clone()
+        };
+        let mut items = vec![];
+        for (idx, value) in matches.into_iter().enumerate() {
+            if idx != 0 {
+                items.push(R::Newline);
+            }
+            if options.to_json {
+                json::format(value, data, &mut items);
+                continue;
+            }
+            let matched = &data[value.start..value.end];
+            items.extend(format_tree(
+                &options,
+                matched,
+                print_debug.as_mut(),
+                &parser,
+            )?);
+        }
+        items
+    } else if options.dialect == Dialect::StackTrace {
+        let lines = stacktrace::collapse(stacktrace::parse(data), &options.collapse_frames);
+        if let Some(debug) = print_debug.as_mut() {
+            writeln!(debug, "==============================")?;
+            writeln!(debug, "{:?}", lines)?;
+        }
+        let mut items = vec![];
+        stacktrace::format(&lines, &mut items);
+        items
+    } else if markup::is_markup(data) {
+        let nodes = markup::parse(data);
+        if let Some(debug) = print_debug.as_mut() {
+            writeln!(debug, "==============================")?;
+            writeln!(debug, "{:?}", nodes)?;
+        }
+        let mut items = vec![];
+        markup::format(&nodes, options.line_width(), &mut items);
+        items
+    } else if let Some(records) = logfmt::parse(data) {
+        if let Some(debug) = print_debug.as_mut() {
+            writeln!(debug, "==============================")?;
+            writeln!(debug, "{:?}", records)?;
+        }
+        let mut items = vec![];
+        for (idx, pairs) in records.iter().enumerate() {
+            if idx != 0 {
+                items.push(R::Newline);
+            }
+            let mut values = vec![];
+            for pair in pairs {
+                values.push(if logfmt::is_nested(pair.value) {
+                    format_tree(options, pair.value, print_debug.as_mut(), &parser)?
+                } else {
+                    vec![R::String(pair.value.to_string())]
+                });
+            }
+            logfmt::format(pairs, values, options.line_width(), &mut items);
+        }
+        items
+    } else if let Some(payloads) = find_payloads(options, data, &parser) {
+        let mut formatted = vec![];
+        for (start, end) in payloads {
+            let items = format_tree(options, &data[start..end], print_debug.as_mut(), &parser)?;
+            formatted.push(((start, end), items));
+        }
+        let mut items = vec![];
+        embedded::format(data, formatted, &mut items);
+        items
+    } else {
+        format_tree(options, data, print_debug.as_mut(), &parser)?
+    })
+}
+
+/// The spans of the structured payloads embedded in `data`, if `embedded_payloads` is set and
+/// there's any prose around them.
+fn find_payloads(
+    options: &Options,
+    data: &str,
+    parser: impl Fn(String) -> (Box<dyn ParseTree>, String),
+) -> Option<Vec<(usize, usize)>> {
+    if !options.embedded_payloads {
+        return None;
+    }
+    let verbatim = VerbatimSpans::find(data, options);
+    let (tree, _) = parser(verbatim.mask(data));
+    let mut payloads = vec![];
+    embedded::find_payloads(&*tree.root_node(), data.as_bytes(), &mut payloads);
+    let trimmed = data.trim();
+    let leading = data.len() - data.trim_start().len();
+    match &payloads[..] {
+        [] => None,
+        [(start, end)] if *start == leading && *end == leading + trimmed.len() => None,
+        _ => Some(payloads),
+    }
+}
+
+/// Writes a structural diff between `old` and `new`, which are parsed the same way as the input
+/// to [`do_format`]. Changed lines are colored with ANSI escapes if `color` is set.
+pub fn do_diff(
+    writer: impl Write,
+    options: &Options,
+    old: String,
+    new: String,
+    color: bool,
+    parser: impl Fn(String) -> (Box<dyn ParseTree>, String),
+) -> Result<()> {
+    let parse = |data: &str| {
+        let verbatim = VerbatimSpans::find(data, options);
+        let (tree, _) = parser(verbatim.mask(data));
+        let root = query::Value::from_tree(&*tree.root_node(), data.as_bytes());
+        root
+    };
+    diff::write_diff((&parse(&old), &old), (&parse(&new), &new), color, writer)
+}
+
+/// Formats part of `data`: the smallest container around `start..end` along with any label in
+/// front of it, or if there isn't one, the top-level expressions which the range overlaps.
+/// Returns the range which was formatted along with its replacement, whose lines are indented
+/// to match the line it starts on. Fails with [`io::ErrorKind::InvalidInput`] if `start..end`
+/// isn't a range of `data` which starts and ends on char boundaries.
+pub fn format_range(
+    data: &str,
+    start: usize,
+    end: usize,
+    options: &Options,
+    parser: impl Fn(String) -> (Box<dyn ParseTree>, String),
+) -> Result<(usize, usize, String)> {
+    if start > end || !data.is_char_boundary(start) || !data.is_char_boundary(end) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}..{} isn't a range of the input", start, end),
+        ));
+    }
+    let verbatim = VerbatimSpans::find(data, options);
+    let (tree, _) = parser(verbatim.mask(data));
+    let root = tree.root_node();
+    let (start, end) = match innermost_container(&*root, start, end) {
+        Some((container_start, container_end)) => (
+            embedded::label_start(data.as_bytes(), container_start),
+            container_end,
+        ),
+        None => match top_level_range(&*root, data.as_bytes(), start, end) {
+            Some(range) => range,
+            None => return Ok((start, end, data[start..end].to_string())),
+        },
+    };
+
+    let items = format_tree(options, &data[start..end], None::<io::Sink>, &parser)?;
+    let mut output = vec![];
+    write_output(items.iter(), &mut output)?;
+    let line = &data[data[..start].rfind('\n').map_or(0, |idx| idx + 1)..];
+    let indent = &line[..line.len() - line.trim_start_matches([' ', '\t']).len()];
+    let mut text = String::new();
+    for (idx, line) in String::from_utf8_lossy(&output).trim().lines().enumerate() {
+        if idx != 0 {
+            text.push('\n');
+            if !line.is_empty() {
+                text.push_str(indent);
+            }
+        }
+        text.push_str(line);
+    }
+    Ok((start, end, text))
+}
+
+/// The span of the innermost container under `node` which contains all of `start..end`.
+fn innermost_container(
+    node: &dyn ParseNode<'_>,
+    start: usize,
+    end: usize,
+) -> Option<(usize, usize)> {
+    let mut cursor = node.walk();
+    if !cursor.goto_first_child() {
+        return None;
+    }
+    loop {
+        let child = cursor.node();
+        let (child_start, child_end) = (child.start_byte(), child.end_byte());
+        if child_start <= start && end <= child_end && child_start < child_end {
+            let this = Some((child_start, child_end)).filter(|_| child.kind() == "container");
+            return innermost_container(&*child, start, end).or(this);
+        }
+        if !cursor.goto_next_sibling() {
+            return None;
+        }
+    }
+}
+
+/// The span of the top-level expressions which overlap `start..end`, or touch it if it's empty.
+/// The top level of the tree is flat, so an expression is a run of nodes without a line break
+/// between them.
+fn top_level_range(
+    root: &dyn ParseNode<'_>,
+    data: &[u8],
+    start: usize,
+    end: usize,
+) -> Option<(usize, usize)> {
+    let mut expressions: Vec<(usize, usize)> = vec![];
+    let mut cursor = root.walk();
+    if cursor.goto_first_child() {
+        loop {
+            let child = cursor.node();
+            let (child_start, child_end) = (child.start_byte(), child.end_byte());
+            match expressions.last_mut() {
+                Some((_, last_end)) if !data[*last_end..child_start].contains(&b'\n') => {
+                    *last_end = child_end;
+                }
+                _ => expressions.push((child_start, child_end)),
+            }
+            if !cursor.goto_next_sibling() {
+                break;
+            }
+        }
+    }
+
+    let overlapping = expressions.into_iter().filter(|&(expr_start, expr_end)| {
+        if start == end {
+            expr_start <= start && start <= expr_end
+        } else {
+            expr_start < end && start < expr_end
+        }
+    });
+    overlapping.fold(None, |range, (expr_start, expr_end)| {
+        Some(range.map_or((expr_start, expr_end), |(first, _)| (first, expr_end)))
+    })
+}
+
+/// The byte ranges of the containers in `data`, from their opening bracket up to and including
+/// their closing one, in the order they start.
+pub fn container_ranges(
+    options: &Options,
+    data: &str,
+    parser: impl Fn(String) -> (Box<dyn ParseTree>, String),
+) -> Vec<(usize, usize)> {
+    fn collect(node: &dyn ParseNode<'_>, out: &mut Vec<(usize, usize)>) {
+        if node.kind() == "container" {
+            out.push((node.start_byte(), node.end_byte()));
+        }
+        let mut cursor = node.walk();
+        if cursor.goto_first_child() {
+            loop {
+                collect(&*cursor.node(), out);
+                if !cursor.goto_next_sibling() {
+                    break;
+                }
+            }
+        }
+    }
+
+    let verbatim = VerbatimSpans::find(data, options);
+    let (tree, _) = parser(verbatim.mask(data));
+    let mut ranges = vec![];
+    collect(&*tree.root_node(), &mut ranges);
+    ranges
+}
+
+/// Formats `data` by way of its parse tree.
+fn format_tree(
+    options: &Options,
+    data: &str,
+    mut print_debug: Option<impl Write>,
+    parser: impl Fn(String) -> (Box<dyn ParseTree>, String),
+) -> Result<Vec<R>> {
+    // Verbatim spans are hidden from the parser, so that they always end up inside a single
+    // `text` node no matter what they contain.
+    let verbatim = VerbatimSpans::find(data, options);
+    let (tree, _) = parser(verbatim.mask(data));
+    if let Some(debug) = print_debug.as_mut() {
+        writeln!(debug, "==============================")?;
+        writeln!(debug, "{}", tree.debug_tree())?;
+    }
+
+    let ctx = Context {
+        data: data.as_bytes(),
+        options,
+        verbatim,
+        parser: &parser,
+        depth: Cell::new(0),
+    };
+    let (items, _) = format_parse_cursor(
+        tree.root_node().walk(),
+        &ctx,
+        0,
+        ctx.data.len(),
+        print_debug.as_mut(),
+    );
+    let items = if options.dialect == Dialect::Sql {
+        items
+    } else {
+        keep_prose_semicolons(items)
+    };
+    let mut items = format_statements(items, true);
+    if options.drop_empty_elements {
+        items = drop_empty_elements(items);
+    }
+    Ok(if options.dialect == Dialect::Sql {
+        sql::format_clauses(items, options.uppercase_keywords)
+    } else {
+        items
+    })
+}
+
 fn write_output<'a>(items: impl IntoIterator<Item = &'a R>, mut writer: impl Write) -> Result<()> {
     let mut indent = 0;
+    // Set after a line comment, which must not have anything else follow it on the same line.
+    let mut needs_newline = false;
     for item in items {
         match item {
+            R::Space if needs_newline => continue,
+            R::Newline | R::Indent | R::Unindent => (),
+            _ if needs_newline => {
+                writeln!(writer)?;
+                for _ in 0..indent {
+                    write!(writer, "  ")?;
+                }
+                needs_newline = false;
+            }
+            _ => (),
+        }
+        match item {
+            R::LineComment(s) => {
+                write!(writer, "{}", s)?;
+                needs_newline = true;
+            }
             R::String(s) => {
                 write!(writer, "{}", s)?;
             }
@@ -416,7 +1492,8 @@ fn write_output<'a>(items: impl IntoIterator<Item = &'a R>, mut writer: impl Wri
                 indent -= 1;
             }
             R::Newline => {
-                write!(writer, "\n")?;
+                needs_newline = false;
+                writeln!(writer)?;
                 for _ in 0..indent {
                     write!(writer, "  ")?;
                 }
@@ -424,13 +1501,14 @@ fn write_output<'a>(items: impl IntoIterator<Item = &'a R>, mut writer: impl Wri
         }
     }
 
-    write!(writer, "\n")?;
+    writeln!(writer)?;
     Ok(())
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 enum R {
     String(String),
+    LineComment(String),
     Delimiter(char, bool),
     Char(char),
     Space,
@@ -442,14 +1520,29 @@ enum R {
 impl R {
     fn len(&self) -> usize {
         match self {
-            R::String(s) => s.len(),
+            R::String(s) | R::LineComment(s) => s.chars().count(),
             _ => 1,
         }
     }
 
-    fn is_newline(&self) -> bool {
+    fn is_blank(&self) -> bool {
+        match self {
+            R::Space | R::Newline => true,
+            R::String(s) => s.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Whether this only affects whitespace.
+    fn is_layout(&self) -> bool {
+        self.is_blank() || matches!(self, R::Indent | R::Unindent)
+    }
+
+    fn breaks_line(&self) -> bool {
         match self {
-            R::Newline => true,
+            R::Newline | R::LineComment(_) => true,
+            // e.g. a multi-line string literal.
+            R::String(s) => s.contains('\n'),
             _ => false,
         }
     }
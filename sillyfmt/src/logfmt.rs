@@ -0,0 +1,129 @@
+//! Formatting for logfmt-style lines, e.g. `level=info msg="request done" dur=12ms`. Each line is
+//! split into its `key=value` pairs, and only values with containers in them are parsed.
+
+use crate::verbatim::quoted_len;
+use crate::R;
+
+#[derive(Debug)]
+pub(crate) struct Pair<'a> {
+    pub(crate) key: &'a str,
+    pub(crate) value: &'a str,
+}
+
+/// Splits every line of `data` into `key=value` pairs, or returns `None` if any line isn't made
+/// up entirely of them. Blank lines are skipped.
+pub(crate) fn parse(data: &str) -> Option<Vec<Vec<Pair<'_>>>> {
+    let records = data
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_record)
+        .collect::<Option<Vec<_>>>()?;
+    if records.is_empty() {
+        None
+    } else {
+        Some(records)
+    }
+}
+
+fn parse_record(line: &str) -> Option<Vec<Pair<'_>>> {
+    let mut pairs = vec![];
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let key_len = rest.find(|c: char| !is_key_char(c)).unwrap_or(rest.len());
+        if key_len == 0 || !rest.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+            return None;
+        }
+        let value = rest[key_len..].strip_prefix('=')?;
+        let value_len = value_len(value)?;
+        pairs.push(Pair {
+            key: &rest[..key_len],
+            value: &value[..value_len],
+        });
+        let after = &value[value_len..];
+        rest = after.trim_start();
+        if !rest.is_empty() && rest.len() == after.len() {
+            // Pairs have to be separated by whitespace.
+            return None;
+        }
+    }
+    // A single pair is more likely to be an assignment than a log line.
+    if pairs.len() < 2 {
+        None
+    } else {
+        Some(pairs)
+    }
+}
+
+fn is_key_char(c: char) -> bool {
+    c.is_alphanumeric() || "_.-/@".contains(c)
+}
+
+/// The length of the value at the start of `s`: either a quoted string, or everything up to the
+/// next whitespace outside of brackets and quotes.
+fn value_len(s: &str) -> Option<usize> {
+    if s.starts_with('"') {
+        return quoted_len(s);
+    }
+    let mut depth = 0usize;
+    let mut pos = 0;
+    while let Some(c) = s[pos..].chars().next() {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth = depth.checked_sub(1)?,
+            '"' => {
+                pos += quoted_len(&s[pos..])?;
+                continue;
+            }
+            c if c.is_whitespace() && depth == 0 => return Some(pos),
+            _ => (),
+        }
+        pos += c.len_utf8();
+    }
+    if depth == 0 {
+        Some(s.len())
+    } else {
+        None
+    }
+}
+
+/// Whether a value has any structure worth formatting, rather than being copied as-is.
+pub(crate) fn is_nested(value: &str) -> bool {
+    !value.starts_with('"') && value.contains(['(', '[', '{'])
+}
+
+/// Lays out a record given its pairs and their formatted values: on one line if it fits in
+/// `line_width`, otherwise one pair per line with the `=`s lined up.
+pub(crate) fn format(pairs: &[Pair<'_>], values: Vec<Vec<R>>, line_width: usize, out: &mut Vec<R>) {
+    let len = pairs
+        .iter()
+        .map(|p| p.key.chars().count() + 2)
+        .sum::<usize>()
+        + values.iter().flatten().map(R::len).sum::<usize>();
+    if len <= line_width && !values.iter().flatten().any(R::breaks_line) {
+        for (idx, (pair, value)) in pairs.iter().zip(values).enumerate() {
+            if idx != 0 {
+                out.push(R::Space);
+            }
+            out.push(R::String(pair.key.to_string()));
+            out.push(R::Char('='));
+            out.extend(value);
+        }
+        return;
+    }
+
+    let width = pairs
+        .iter()
+        .map(|p| p.key.chars().count())
+        .max()
+        .unwrap_or(0);
+    for (idx, (pair, value)) in pairs.iter().zip(values).enumerate() {
+        if idx != 0 {
+            out.push(R::Newline);
+        }
+        out.push(R::String(format!("{:width$} =", pair.key, width = width)));
+        if !pair.value.is_empty() {
+            out.push(R::Space);
+        }
+        out.extend(value);
+    }
+}
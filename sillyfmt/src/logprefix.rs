@@ -0,0 +1,54 @@
+//! Log lines with a prefix, e.g. `2020-05-05T10:00:00Z INFO [worker-3] state={...}`. The prefix
+//! is kept as it is, and only the payload after it is formatted.
+
+use crate::options::LogPrefix;
+use crate::R;
+
+/// Splits `data` into records, each starting at a line with a prefix. Lines without one belong
+/// to the record before them, or to a record of their own without a prefix at the very start.
+pub(crate) fn split<'a>(prefix: &LogPrefix, data: &'a str) -> Vec<(Option<&'a str>, String)> {
+    let mut records: Vec<(Option<&str>, String)> = vec![];
+    for line in data.lines() {
+        match prefix.0.find(line) {
+            Some(m) if !m.as_str().trim().is_empty() => {
+                records.push((
+                    Some(m.as_str().trim()),
+                    line[m.end()..].trim_start().to_string(),
+                ));
+            }
+            _ => match records.last_mut() {
+                Some((_, payload)) => {
+                    payload.push('\n');
+                    payload.push_str(line);
+                }
+                None => records.push((None, line.to_string())),
+            },
+        }
+    }
+    records
+}
+
+/// Lays out a record: the payload follows the prefix if it fits on one line, and is indented
+/// underneath it otherwise.
+pub(crate) fn format(prefix: Option<&str>, payload: Vec<R>, out: &mut Vec<R>) {
+    let prefix = match prefix {
+        Some(prefix) => prefix,
+        None => {
+            out.extend(payload);
+            return;
+        }
+    };
+    out.push(R::String(prefix.to_string()));
+    if payload.iter().all(R::is_layout) {
+        return;
+    }
+    if payload.iter().any(R::breaks_line) {
+        out.push(R::Indent);
+        out.push(R::Newline);
+        out.extend(payload);
+        out.push(R::Unindent);
+    } else {
+        out.push(R::Space);
+        out.extend(payload);
+    }
+}
@@ -0,0 +1,388 @@
+//! Formatting for XML and HTML, which nests child elements under their parents and keeps short
+//! elements with text content on one line.
+
+use crate::R;
+
+/// HTML elements whose bodies are raw text, up to their closing tag.
+const RAW_TEXT_ELEMENTS: &[&str] = &["script", "style"];
+
+/// HTML elements which never have a closing tag.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+#[derive(Debug)]
+enum Token {
+    Open {
+        name: String,
+        tag: String,
+        self_closing: bool,
+    },
+    Close {
+        name: String,
+        tag: String,
+    },
+    Text(String),
+    /// Comments, doctypes, processing instructions and CDATA sections.
+    Verbatim(String),
+}
+
+#[derive(Debug)]
+pub(crate) enum Node {
+    Element {
+        open: String,
+        children: Vec<Node>,
+        close: Option<String>,
+    },
+    Text(String),
+    Verbatim(String),
+}
+
+/// Whether `data` looks like a markup document or fragment, rather than code which happens to
+/// contain `<`.
+pub(crate) fn is_markup(data: &str) -> bool {
+    let data = data.trim();
+    data.starts_with('<')
+        && data.ends_with('>')
+        && tokenize(data).iter().any(|t| {
+            matches!(
+                t,
+                Token::Close { .. }
+                    | Token::Open {
+                        self_closing: true,
+                        ..
+                    }
+            )
+        })
+}
+
+pub(crate) fn parse(data: &str) -> Vec<Node> {
+    // Each entry is an element which hasn't been closed yet, along with its children so far.
+    let mut stack: Vec<(String, String, Vec<Node>)> = vec![];
+    let mut root = vec![];
+
+    for token in tokenize(data) {
+        let node = match token {
+            Token::Open {
+                name,
+                tag,
+                self_closing,
+            } => {
+                if self_closing || VOID_ELEMENTS.contains(&&*name.to_lowercase()) {
+                    Node::Element {
+                        open: tag,
+                        children: vec![],
+                        close: None,
+                    }
+                } else {
+                    stack.push((name, tag, vec![]));
+                    continue;
+                }
+            }
+            Token::Close { name, tag } => {
+                match stack.iter().rposition(|(open, _, _)| *open == name) {
+                    Some(idx) => {
+                        // Anything opened since then was never closed, so it ends here too.
+                        while stack.len() > idx + 1 {
+                            let (_, open, children) = stack.pop().unwrap();
+                            push(&mut stack, &mut root, unclosed(open, children));
+                        }
+                        let (_, open, children) = stack.pop().unwrap();
+                        Node::Element {
+                            open,
+                            children,
+                            close: Some(tag),
+                        }
+                    }
+                    None => Node::Verbatim(tag),
+                }
+            }
+            Token::Text(text) => Node::Text(text),
+            Token::Verbatim(text) => Node::Verbatim(text),
+        };
+        push(&mut stack, &mut root, node);
+    }
+    while let Some((_, open, children)) = stack.pop() {
+        push(&mut stack, &mut root, unclosed(open, children));
+    }
+    root
+}
+
+fn push(stack: &mut [(String, String, Vec<Node>)], root: &mut Vec<Node>, node: Node) {
+    match stack.last_mut() {
+        Some((_, _, children)) => children.push(node),
+        None => root.push(node),
+    }
+}
+
+fn unclosed(open: String, children: Vec<Node>) -> Node {
+    Node::Element {
+        open,
+        children,
+        close: None,
+    }
+}
+
+/// Lays out each element on lines of its own, except for elements with text content which fit in
+/// `line_width`; those keep their inline children on the same line, e.g. `<p>Hello <b>world</b></p>`.
+pub(crate) fn format(nodes: &[Node], line_width: usize, out: &mut Vec<R>) {
+    let mut first = true;
+    for node in nodes {
+        if matches!(node, Node::Text(text) if text.trim().is_empty()) {
+            continue;
+        }
+        if !first {
+            out.push(R::Newline);
+        }
+        first = false;
+        format_node(node, line_width, out);
+    }
+}
+
+fn format_node(node: &Node, line_width: usize, out: &mut Vec<R>) {
+    match node {
+        Node::Text(text) => out.push(R::String(text.trim().to_string())),
+        Node::Verbatim(text) => out.push(R::String(text.clone())),
+        Node::Element {
+            open,
+            children,
+            close,
+        } => {
+            out.push(R::String(open.clone()));
+            let has_text = children
+                .iter()
+                .any(|child| matches!(child, Node::Text(text) if !text.trim().is_empty()));
+            let content = children.iter().map(inline).collect::<Option<String>>();
+            match content {
+                _ if children.is_empty() => {}
+                Some(content) if has_text && open.len() + content.trim().len() <= line_width => {
+                    out.push(R::String(content.trim().to_string()));
+                }
+                _ => {
+                    out.push(R::Indent);
+                    out.push(R::Newline);
+                    format(children, line_width, out);
+                    out.push(R::Unindent);
+                    if close.is_some() {
+                        out.push(R::Newline);
+                    }
+                }
+            }
+            if let Some(close) = close {
+                out.push(R::String(close.clone()));
+            }
+        }
+    }
+}
+
+/// Renders `node` on a single line, or `None` if it has to be laid out over several.
+fn inline(node: &Node) -> Option<String> {
+    match node {
+        Node::Text(text) => Some(text.clone()),
+        Node::Verbatim(_) => None,
+        Node::Element {
+            open,
+            children,
+            close,
+        } => {
+            let content = children.iter().map(inline).collect::<Option<String>>()?;
+            match close {
+                Some(close) => Some(format!("{}{}{}", open, content, close)),
+                None if children.is_empty() => Some(open.clone()),
+                None => None,
+            }
+        }
+    }
+}
+
+/// The byte ranges of the outermost elements in `data` which have closing tags, for markup
+/// embedded in other text, e.g. the `<a><b>1</b></a>` in `resp: <a><b>1</b></a>`.
+pub(crate) fn element_spans(data: &str) -> Vec<(usize, usize)> {
+    let mut spans: Vec<(usize, usize)> = vec![];
+    let mut open: Vec<(String, usize)> = vec![];
+    for (start, end, token) in find_tags(data) {
+        match token {
+            Token::Open {
+                name,
+                self_closing: false,
+                ..
+            } if !VOID_ELEMENTS.contains(&&*name.to_lowercase()) => open.push((name, start)),
+            Token::Close { name, .. } => {
+                if let Some(idx) = open.iter().rposition(|(open, _)| *open == name) {
+                    let (_, start) = open[idx];
+                    open.truncate(idx);
+                    // Elements are closed from the inside out, so this one contains any spans
+                    // found since it was opened.
+                    while spans.last().is_some_and(|span| span.0 >= start) {
+                        spans.pop();
+                    }
+                    spans.push((start, end));
+                }
+            }
+            _ => (),
+        }
+    }
+    spans
+}
+
+/// Finds the tags in `data` along with their byte ranges. The bodies of raw text elements like
+/// `<script>` are skipped up to their closing tag, since a `<` in them isn't a tag.
+fn find_tags(data: &str) -> Vec<(usize, usize, Token)> {
+    let mut tags = vec![];
+    let mut pos = 0;
+    while let Some(idx) = data[pos..].find('<') {
+        let start = pos + idx;
+        let Some((token, len)) = match_tag(&data[start..]) else {
+            // Not actually a tag, e.g. `a < b`.
+            pos = start + 1;
+            continue;
+        };
+        pos = start + len;
+        if let Some(close) = raw_text_close(&token) {
+            pos = data[pos..]
+                .to_ascii_lowercase()
+                .find(&close)
+                .map_or(data.len(), |idx| pos + idx);
+        }
+        tags.push((start, start + len, token));
+    }
+    tags
+}
+
+/// If `token` opens an element whose body is raw text rather than markup, the start of the tag
+/// which closes it, in lowercase.
+fn raw_text_close(token: &Token) -> Option<String> {
+    match token {
+        Token::Open {
+            name,
+            self_closing: false,
+            ..
+        } if RAW_TEXT_ELEMENTS.contains(&&*name.to_ascii_lowercase()) => {
+            Some(format!("</{}", name.to_ascii_lowercase()))
+        }
+        _ => None,
+    }
+}
+
+fn tokenize(data: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut text_start = 0;
+    let mut raw = false;
+    for (start, end, token) in find_tags(data) {
+        push_text(&data[text_start..start], raw, &mut tokens);
+        raw = raw_text_close(&token).is_some();
+        tokens.push(token);
+        text_start = end;
+    }
+    push_text(&data[text_start..], raw, &mut tokens);
+    tokens
+}
+
+/// Adds the text between two tags. Raw text is kept as it is apart from the whitespace around it,
+/// and otherwise runs of whitespace are collapsed into single spaces.
+fn push_text(text: &str, raw: bool, tokens: &mut Vec<Token>) {
+    if raw {
+        let text = text.trim();
+        if text.contains('\n') {
+            tokens.push(Token::Verbatim(text.to_string()));
+        } else if !text.is_empty() {
+            tokens.push(Token::Text(text.to_string()));
+        }
+        return;
+    }
+    // Keep a single space at either end, since it separates the text from inline siblings.
+    let mut normalized = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.ends_with(char::is_whitespace) && !normalized.is_empty() {
+        normalized.push(' ');
+    }
+    if text.starts_with(char::is_whitespace) {
+        normalized.insert(0, ' ');
+    }
+    if !normalized.is_empty() {
+        tokens.push(Token::Text(normalized));
+    }
+}
+
+/// Matches the tag at the start of `s`, returning it along with its length in bytes.
+fn match_tag(s: &str) -> Option<(Token, usize)> {
+    for (open, close) in &[
+        ("<!--", "-->"),
+        ("<![CDATA[", "]]>"),
+        ("<?", "?>"),
+        ("<!", ">"),
+    ] {
+        if let Some(body) = s.strip_prefix(open) {
+            let len = body.find(close)? + open.len() + close.len();
+            return Some((Token::Verbatim(s[..len].to_string()), len));
+        }
+    }
+
+    let (is_close, body) = match s[1..].strip_prefix('/') {
+        Some(body) => (true, body),
+        None => (false, &s[1..]),
+    };
+    if !body.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+        return None;
+    }
+    let name_len = body
+        .find(|c: char| c.is_whitespace() || c == '/' || c == '>')
+        .unwrap_or(body.len());
+    let name = &body[..name_len];
+
+    // Normalize the whitespace between attributes, including around the `=` in `a = "b"`.
+    let mut attrs = vec![];
+    let mut rest = &body[name_len..];
+    let self_closing = loop {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix('>') {
+            rest = after;
+            break false;
+        } else if let Some(after) = rest.strip_prefix("/>") {
+            rest = after;
+            break true;
+        }
+        let attr_len = rest
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '>' || c == '/')
+            .unwrap_or(rest.len())
+            .max(rest.chars().next()?.len_utf8());
+        let mut attr = rest[..attr_len].to_string();
+        rest = &rest[attr_len..];
+        if let Some(value) = rest.trim_start().strip_prefix('=') {
+            let value = value.trim_start();
+            let value_len = match value.chars().next() {
+                Some(q) if q == '"' || q == '\'' => value[1..].find(q)? + 2,
+                _ => value
+                    .find(|c: char| c.is_whitespace() || c == '>')
+                    .unwrap_or(value.len()),
+            };
+            attr.push('=');
+            attr.push_str(&value[..value_len]);
+            rest = &value[value_len..];
+        }
+        attrs.push(attr);
+    };
+    let end = s.len() - rest.len();
+
+    let mut tag = String::from(if is_close { "</" } else { "<" });
+    tag.push_str(name);
+    for attr in &attrs {
+        tag.push(' ');
+        tag.push_str(attr);
+    }
+    tag.push_str(if self_closing { "/>" } else { ">" });
+
+    let token = if is_close {
+        Token::Close {
+            name: name.to_string(),
+            tag,
+        }
+    } else {
+        Token::Open {
+            name: name.to_string(),
+            tag,
+            self_closing,
+        }
+    };
+    Some((token, end))
+}
@@ -0,0 +1,255 @@
+use std::str::FromStr;
+
+use regex::Regex;
+
+use crate::Query;
+
+/// Knobs which control how `sillyfmt` interprets its input.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub dialect: Dialect,
+    /// Render `\n` escapes in string literals as actual (indented) line breaks. The output is
+    /// easier to read, but no longer a faithful copy of the string.
+    pub expand_escapes: bool,
+    /// In the stack trace dialect, frames containing any of these (e.g. `tokio::` or
+    /// `java.lang.reflect.`) are framework frames, and runs of them are collapsed into a single
+    /// `... N frames omitted` line.
+    pub collapse_frames: Vec<String>,
+    /// Line up the values of `key: value`, `key = value` and `key => value` entries in containers
+    /// which are split one entry per line.
+    pub align_values: bool,
+    /// What to do with the comma after the last element of a container.
+    pub trailing_commas: TrailingComma,
+    /// Remove the commas around empty elements, e.g. turning `a, , c` into `a, c`.
+    pub drop_empty_elements: bool,
+    /// Replace runs of identical elements with a count, e.g. `[0 × 512]`.
+    pub collapse_repeats: bool,
+    /// Only show this many elements of each container, followed by `... N more`.
+    pub max_elements: Option<usize>,
+    /// Fold containers nested more than this deep into a summary like `{… 3 items}`.
+    pub max_depth: Option<usize>,
+    /// Instead of formatting the whole input, only print the parts of it which match this query.
+    pub select: Option<Query>,
+    /// Print the input (or whatever `select` matches) as JSON instead of formatting it.
+    pub to_json: bool,
+    /// Keep the prefix of each log line matching this intact, e.g. its timestamp, level and
+    /// thread, and only format what comes after it. Lines which don't match are treated as part
+    /// of the line before.
+    pub log_prefix: Option<LogPrefix>,
+    /// Only format the structured parts of the input, e.g. the `{...}` in
+    /// `Got response: {...} after 12ms`, and copy the prose around them as it is.
+    pub embedded_payloads: bool,
+    /// Format strings which hold escaped JSON or other containers, e.g. `"{\"a\": 1}"`, as the
+    /// containers themselves.
+    pub unwrap_encoded_strings: bool,
+    /// Show hex, base64 and byte array blobs which decode to text as that text.
+    pub decode_blobs: bool,
+    /// Show hex, base64 and byte array blobs in full, instead of cutting them short once they're
+    /// longer than a line's worth and noting how many bytes they hold.
+    pub full_blobs: bool,
+    /// Whether containers which are kept on one line get spaces inside their brackets.
+    pub bracket_spacing: BracketSpacing,
+    /// In the SQL dialect, write keywords like `select` and `from` in uppercase.
+    pub uppercase_keywords: bool,
+    /// How long a line can get before containers, calls, tables, logfmt records and markup
+    /// elements are split over several lines. Defaults to 80 characters.
+    pub line_width: Option<usize>,
+}
+
+/// The line width used when `line_width` isn't set.
+const DEFAULT_LINE_WIDTH: usize = 80;
+
+impl Options {
+    pub(crate) fn line_width(&self) -> usize {
+        self.line_width.unwrap_or(DEFAULT_LINE_WIDTH)
+    }
+
+    /// How long a container's contents can be while it's kept on one line. Containers get less
+    /// room than calls, since they're more often nested several deep: 32 characters at the
+    /// default width.
+    pub(crate) fn inline_width(&self) -> usize {
+        self.line_width() * 2 / 5
+    }
+}
+
+/// The flavor of text being formatted, which determines things like the comment syntax.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Dialect {
+    /// `// line` and `/* block */` comments, as in C, Rust, JavaScript and friends.
+    #[default]
+    Generic,
+    /// `# line` comments, as in shell scripts, Python, Ruby, YAML and TOML.
+    Shell,
+    /// `-- line` and `/* block */` comments and `'single-quoted'` strings, with each clause of a
+    /// query on its own line.
+    Sql,
+    /// Java exceptions, Python tracebacks and Rust backtraces, formatted one frame per line.
+    StackTrace,
+}
+
+impl Dialect {
+    /// The marker which starts a comment running to the end of the line.
+    pub(crate) fn line_comment(self) -> Option<&'static str> {
+        match self {
+            Dialect::Generic => Some("//"),
+            Dialect::Shell => Some("#"),
+            Dialect::Sql => Some("--"),
+            Dialect::StackTrace => None,
+        }
+    }
+
+    /// The markers which open and close a block comment, if the dialect has them.
+    pub(crate) fn block_comment(self) -> Option<(&'static str, &'static str)> {
+        match self {
+            Dialect::Generic | Dialect::Sql => Some(("/*", "*/")),
+            Dialect::Shell | Dialect::StackTrace => None,
+        }
+    }
+}
+
+/// How trailing commas are handled. Containers without any commas are never given one, and a
+/// lone trailing comma is always kept, as in `(x,)`, since either could change their meaning.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum TrailingComma {
+    /// Keep trailing commas exactly where they were found.
+    #[default]
+    Preserve,
+    /// Add trailing commas to containers which are split over several lines, and remove them
+    /// from those kept on one line.
+    Vertical,
+    /// Remove trailing commas, other than lone ones.
+    Never,
+}
+
+impl FromStr for TrailingComma {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(TrailingComma::Preserve),
+            "vertical" => Ok(TrailingComma::Vertical),
+            "never" => Ok(TrailingComma::Never),
+            _ => Err(format!("unknown trailing comma style {:?}", s)),
+        }
+    }
+}
+
+/// A pattern for the prefix of a log line, which is matched at the start of each line.
+#[derive(Clone, Debug)]
+pub struct LogPrefix(pub(crate) Regex);
+
+/// Matches a timestamp and/or level, optionally followed by a bracketed thread name, as in
+/// `2020-05-05T10:00:00Z INFO [worker-3]` or `WARN [main]`.
+const DEFAULT_LOG_PREFIX: &str = concat!(
+    r"(?:(?:\d{4}-\d{2}-\d{2}[T ])?\d{2}:\d{2}:\d{2}(?:[.,]\d+)?(?:Z|[+-]\d{2}:?\d{2})?",
+    r"(?:\s+\[?(?:TRACE|DEBUG|INFO|WARN|WARNING|ERROR|FATAL|CRITICAL)\]?)?",
+    r"|\[?(?:TRACE|DEBUG|INFO|WARN|WARNING|ERROR|FATAL|CRITICAL)\]?)",
+    r"(?:\s+\[[\w.:/@#-]+\])?(?:\s|$)",
+);
+
+impl Default for LogPrefix {
+    fn default() -> Self {
+        DEFAULT_LOG_PREFIX.parse().unwrap()
+    }
+}
+
+impl FromStr for LogPrefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Regex::new(&format!("^(?:{})", s))
+            .map(LogPrefix)
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// The [`Spacing`] for each kind of bracket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct BracketSpacing {
+    pub parens: Spacing,
+    pub brackets: Spacing,
+    pub braces: Spacing,
+    pub angles: Spacing,
+}
+
+impl BracketSpacing {
+    pub(crate) fn for_open(self, open: char) -> Spacing {
+        match open {
+            '(' => self.parens,
+            '[' => self.brackets,
+            '{' => self.braces,
+            _ => self.angles,
+        }
+    }
+}
+
+/// Parses a comma-separated list of `kind=spacing`, e.g. `parens=never,braces=always`, where
+/// `kind` is `parens`, `brackets`, `braces`, `angles` or `all`. Kinds which aren't listed keep the
+/// default spacing.
+impl FromStr for BracketSpacing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut spacing = BracketSpacing::default();
+        for setting in s.split(',') {
+            let (kind, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected kind=spacing, got {:?}", setting))?;
+            let value = value.parse()?;
+            match kind {
+                "parens" => spacing.parens = value,
+                "brackets" => spacing.brackets = value,
+                "braces" => spacing.braces = value,
+                "angles" => spacing.angles = value,
+                "all" => {
+                    spacing = BracketSpacing {
+                        parens: value,
+                        brackets: value,
+                        braces: value,
+                        angles: value,
+                    }
+                }
+                _ => return Err(format!("unknown bracket kind {:?}", kind)),
+            }
+        }
+        Ok(spacing)
+    }
+}
+
+/// Whether there are spaces just inside the brackets of a container, as in `{ a: b }`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Spacing {
+    /// Only if the container holds more than a few characters, so `(a)` stays as it is but
+    /// `{ a: b }` is padded.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for Spacing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Spacing::Auto),
+            "always" => Ok(Spacing::Always),
+            "never" => Ok(Spacing::Never),
+            _ => Err(format!("unknown spacing {:?}", s)),
+        }
+    }
+}
+
+impl FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "generic" => Ok(Dialect::Generic),
+            "shell" => Ok(Dialect::Shell),
+            "sql" => Ok(Dialect::Sql),
+            "stacktrace" => Ok(Dialect::StackTrace),
+            _ => Err(format!("unknown dialect {:?}", s)),
+        }
+    }
+}
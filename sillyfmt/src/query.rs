@@ -0,0 +1,288 @@
+//! Path queries like `.friends[1].name` or `**.guid`, which pick pieces out of the parse tree.
+//! Containers are treated as lists of elements, and elements of the form `key: value` or
+//! `key = value` can also be looked up by their key.
+
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use crate::verbatim::unescape;
+use crate::ParseNode;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Step {
+    /// `.name`: the elements with this key.
+    Field(String),
+    /// `[n]`: the nth element.
+    Index(usize),
+    /// `.*` or `[*]`: every element.
+    Elements,
+    /// `**`: the value itself along with everything inside it, at any depth.
+    Descendants,
+}
+
+/// A parsed path query.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Query(Vec<Step>);
+
+impl FromStr for Query {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut steps = vec![];
+        let mut rest = s.trim();
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix("**") {
+                steps.push(Step::Descendants);
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix('[') {
+                let end = after
+                    .find(']')
+                    .ok_or_else(|| format!("unclosed [ in {:?}", s))?;
+                steps.push(match after[..end].trim() {
+                    "*" => Step::Elements,
+                    index => Step::Index(
+                        index
+                            .parse()
+                            .map_err(|_| format!("invalid index {:?} in {:?}", index, s))?,
+                    ),
+                });
+                rest = &after[end + 1..];
+            } else {
+                // The leading `.` is optional for the first step.
+                let after = match rest.strip_prefix('.') {
+                    Some(after) => after,
+                    None if steps.is_empty() => rest,
+                    None => return Err(format!("expected . or [ at {:?} in {:?}", rest, s)),
+                };
+                if after.starts_with("**") || after.starts_with('[') || after.is_empty() {
+                    rest = after;
+                    continue;
+                }
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                let name = unquote(&after[..end]);
+                steps.push(if name == "*" {
+                    Step::Elements
+                } else {
+                    Step::Field(name.to_string())
+                });
+                rest = &after[end..];
+            }
+        }
+        Ok(Query(steps))
+    }
+}
+
+/// A value in the input, along with its elements if it's a container.
+#[derive(Debug)]
+pub(crate) struct Value {
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    /// The opening bracket, if this is a container.
+    pub(crate) open: Option<char>,
+    /// The text before the opening bracket, as in `Foo { x: 1 }`.
+    pub(crate) label: Option<(usize, usize)>,
+    pub(crate) elements: Vec<(Option<String>, Value)>,
+}
+
+enum Item {
+    Text(usize, usize),
+    Symbol(String, usize, usize),
+    Comma,
+    Container(Value),
+}
+
+impl Value {
+    /// Builds the values in the tree rooted at `root`. A lone container at the top level is the
+    /// root itself; otherwise, the top level is treated as an unbracketed container.
+    pub(crate) fn from_tree(root: &dyn ParseNode<'_>, data: &[u8]) -> Value {
+        let mut items = vec![];
+        flatten(root, data, &mut items);
+        let mut elements = split_elements(items, data);
+        if elements.len() == 1 && elements[0].0.is_none() && !elements[0].1.elements.is_empty() {
+            return elements.pop().unwrap().1;
+        }
+        Value {
+            start: root.start_byte(),
+            end: root.end_byte(),
+            open: None,
+            label: None,
+            elements,
+        }
+    }
+
+    /// The value itself, or its only element if it's an unbracketed top level with just one
+    /// element in it.
+    pub(crate) fn unwrap_top_level(&self) -> &Value {
+        match &self.elements[..] {
+            [(None, inner)] if self.open.is_none() => inner,
+            _ => self,
+        }
+    }
+
+    /// Runs `query` starting from this value, returning the matches in order.
+    pub(crate) fn select(&self, query: &Query) -> Vec<&Value> {
+        let mut matches = vec![self];
+        for step in &query.0 {
+            let mut next = vec![];
+            for value in matches {
+                match step {
+                    Step::Field(name) => next.extend(
+                        value
+                            .fields()
+                            .iter()
+                            .filter(|(key, _)| key.as_deref() == Some(name))
+                            .map(|(_, v)| v),
+                    ),
+                    Step::Index(idx) => next.extend(value.elements.get(*idx).map(|(_, v)| v)),
+                    Step::Elements => next.extend(value.elements.iter().map(|(_, v)| v)),
+                    Step::Descendants => value.descendants(&mut next),
+                }
+            }
+            // Looking through wrappers can reach the same value more than once.
+            let mut seen = HashSet::new();
+            next.retain(|value| seen.insert(*value as *const Value));
+            matches = next;
+        }
+        matches
+    }
+
+    /// The keyed elements of this value, looking through wrappers like the `Some(...)` in
+    /// `Some(Foo { x: 1 })`.
+    fn fields(&self) -> &[(Option<String>, Value)] {
+        match &self.elements[..] {
+            [(None, inner)] if !inner.elements.is_empty() => inner.fields(),
+            elements => elements,
+        }
+    }
+
+    fn descendants<'a>(&'a self, out: &mut Vec<&'a Value>) {
+        out.push(self);
+        for (_, value) in &self.elements {
+            value.descendants(out);
+        }
+    }
+}
+
+/// Collects the items under `node`, looking through everything except containers.
+fn flatten(node: &dyn ParseNode<'_>, data: &[u8], out: &mut Vec<Item>) {
+    match node.kind().as_str() {
+        "container" => {
+            let mut items = vec![];
+            let mut cursor = node.walk();
+            if cursor.goto_first_child() {
+                loop {
+                    match cursor.field_name().as_deref() {
+                        Some("open") | Some("close") => (),
+                        _ => flatten(&*cursor.node(), data, &mut items),
+                    }
+                    if !cursor.goto_next_sibling() {
+                        break;
+                    }
+                }
+            }
+            out.push(Item::Container(Value {
+                start: node.start_byte(),
+                end: node.end_byte(),
+                open: Some(char::from(data[node.start_byte()])),
+                label: None,
+                elements: split_elements(items, data),
+            }));
+        }
+        "," => out.push(Item::Comma),
+        "symbol" | "conflicting_symbol" => out.push(Item::Symbol(
+            node.utf8_text(data),
+            node.start_byte(),
+            node.end_byte(),
+        )),
+        _ => {
+            let mut cursor = node.walk();
+            if cursor.goto_first_child() {
+                loop {
+                    flatten(&*cursor.node(), data, out);
+                    if !cursor.goto_next_sibling() {
+                        break;
+                    }
+                }
+            } else if node.start_byte() < node.end_byte() {
+                out.push(Item::Text(node.start_byte(), node.end_byte()));
+            }
+        }
+    }
+}
+
+/// Splits a container's items into its elements, separating out their keys.
+fn split_elements(items: Vec<Item>, data: &[u8]) -> Vec<(Option<String>, Value)> {
+    let mut elements = vec![];
+    let mut element = vec![];
+    for item in items.into_iter().chain(Some(Item::Comma)) {
+        match item {
+            Item::Comma if element.is_empty() => (),
+            Item::Comma => elements.push(to_element(std::mem::take(&mut element), data)),
+            item => element.push(item),
+        }
+    }
+    elements
+}
+
+fn to_element(mut items: Vec<Item>, data: &[u8]) -> (Option<String>, Value) {
+    let separator = items
+        .iter()
+        .position(|item| matches!(item, Item::Symbol(s, ..) if s == ":" || s == "=" || s == "=>"));
+    let key = match separator {
+        Some(idx) if idx > 0 => {
+            let key = String::from_utf8_lossy(&data[span(&items[0]).0..span(&items[idx - 1]).1]);
+            let key = key.trim();
+            // Quoted keys are unescaped like string values, so `"a\"b"` is the key `a"b`.
+            let key = match unquote(key) {
+                inner if inner.len() != key.len() => unescape(inner),
+                inner => inner.to_string(),
+            };
+            items.drain(..=idx);
+            Some(key)
+        }
+        _ => None,
+    };
+
+    let (start, end) = match (items.first(), items.last()) {
+        (Some(first), Some(last)) => (span(first).0, span(last).1),
+        _ => (0, 0),
+    };
+    // A container with a label, as in `Foo { x: 1 }`, is looked into like the container itself.
+    let value = match items.pop() {
+        Some(Item::Container(value)) if items.iter().all(|i| matches!(i, Item::Text(..))) => {
+            Value {
+                start,
+                end,
+                open: value.open,
+                label: items.last().map(|last| (start, span(last).1)),
+                elements: value.elements,
+            }
+        }
+        _ => Value {
+            start,
+            end,
+            open: None,
+            label: None,
+            elements: vec![],
+        },
+    };
+    (key, value)
+}
+
+fn span(item: &Item) -> (usize, usize) {
+    match item {
+        Item::Text(start, end) | Item::Symbol(_, start, end) => (*start, *end),
+        Item::Container(value) => (value.start, value.end),
+        // Elements never contain commas.
+        Item::Comma => (0, 0),
+    }
+}
+
+pub(crate) fn unquote(s: &str) -> &str {
+    for quote in &['"', '\''] {
+        if let Some(inner) = s.strip_prefix(*quote).and_then(|s| s.strip_suffix(*quote)) {
+            return inner;
+        }
+    }
+    s
+}
@@ -0,0 +1,189 @@
+//! Clause layout for SQL: lines are broken at the keywords which start each clause, while the
+//! parentheses and commas are laid out like any other container.
+
+use crate::R;
+
+/// Keywords which start a clause on their own.
+const CLAUSES: &[&str] = &[
+    "SELECT",
+    "FROM",
+    "WHERE",
+    "HAVING",
+    "LIMIT",
+    "OFFSET",
+    "UNION",
+    "INTERSECT",
+    "EXCEPT",
+    "VALUES",
+    "SET",
+    "RETURNING",
+    "WITH",
+    "INSERT",
+    "UPDATE",
+    "DELETE",
+    "JOIN",
+];
+
+/// Keywords which can come before `JOIN`.
+const JOIN_MODIFIERS: &[&str] = &[
+    "LEFT", "RIGHT", "FULL", "INNER", "OUTER", "CROSS", "NATURAL",
+];
+
+/// Every keyword which is uppercased when `uppercase_keywords` is set.
+const KEYWORDS: &[&str] = &[
+    "ALL",
+    "AND",
+    "AS",
+    "ASC",
+    "BETWEEN",
+    "BY",
+    "CASE",
+    "CROSS",
+    "DELETE",
+    "DESC",
+    "DISTINCT",
+    "ELSE",
+    "END",
+    "EXCEPT",
+    "EXISTS",
+    "FROM",
+    "FULL",
+    "GROUP",
+    "HAVING",
+    "ILIKE",
+    "IN",
+    "INNER",
+    "INSERT",
+    "INTERSECT",
+    "INTO",
+    "IS",
+    "JOIN",
+    "LEFT",
+    "LIKE",
+    "LIMIT",
+    "NATURAL",
+    "NOT",
+    "NULL",
+    "OFFSET",
+    "ON",
+    "OR",
+    "ORDER",
+    "OUTER",
+    "OVER",
+    "PARTITION",
+    "RETURNING",
+    "RIGHT",
+    "SELECT",
+    "SET",
+    "THEN",
+    "UNION",
+    "UPDATE",
+    "USING",
+    "VALUES",
+    "WHEN",
+    "WHERE",
+    "WITH",
+];
+
+/// Whether `word` is a SQL keyword, in any case.
+pub(crate) fn is_keyword(word: &str) -> bool {
+    KEYWORDS.contains(&&*word.to_uppercase())
+}
+
+/// Starts each clause on a new line, with any lines it wraps onto indented underneath it.
+/// Anything inside brackets is left alone, since containers format their own contents.
+pub(crate) fn format_clauses(items: Vec<R>, uppercase_keywords: bool) -> Vec<R> {
+    let mut out: Vec<R> = Vec::with_capacity(items.len());
+    let mut in_clause = false;
+    // The number of words left in the clause keyword being copied, e.g. `BY` in `GROUP BY`.
+    let mut keyword_words = 0;
+    let mut depth = 0usize;
+    for (idx, item) in items.iter().enumerate() {
+        let word = match item {
+            R::String(s) if !s.is_empty() && depth == 0 => s,
+            R::Char('(') | R::Char('[') | R::Char('{') => {
+                depth += 1;
+                out.push(item.clone());
+                continue;
+            }
+            R::Char(')') | R::Char(']') | R::Char('}') => {
+                depth = depth.saturating_sub(1);
+                out.push(item.clone());
+                continue;
+            }
+            R::Char(';') if in_clause => {
+                out.push(R::Char(';'));
+                out.push(R::Unindent);
+                in_clause = false;
+                continue;
+            }
+            _ => {
+                out.push(item.clone());
+                continue;
+            }
+        };
+
+        if keyword_words == 0 {
+            keyword_words = clause_len(&items[idx..]);
+            if keyword_words > 0 {
+                while out.last().is_some_and(R::is_blank) {
+                    out.pop();
+                }
+                if in_clause {
+                    out.push(R::Unindent);
+                }
+                if !out.iter().all(R::is_blank) {
+                    out.push(R::Newline);
+                }
+                out.push(R::Indent);
+                in_clause = true;
+            }
+        }
+        keyword_words = keyword_words.saturating_sub(1);
+
+        let upper = word.to_uppercase();
+        if uppercase_keywords && KEYWORDS.contains(&&*upper) {
+            out.push(R::String(upper));
+        } else {
+            out.push(R::String(word.clone()));
+        }
+    }
+    if in_clause {
+        out.push(R::Unindent);
+    }
+    out
+}
+
+/// The number of words in the clause keyword at the start of `items`, or zero if it doesn't start
+/// a clause.
+fn clause_len(items: &[R]) -> usize {
+    let words = items
+        .iter()
+        .take_while(|it| matches!(it, R::String(_) | R::Space | R::Newline))
+        .filter_map(|it| match it {
+            R::String(s) if !s.is_empty() => Some(s.to_uppercase()),
+            _ => None,
+        })
+        .take(4)
+        .collect::<Vec<_>>();
+    match words.first().map(|w| &w[..]) {
+        Some(w) if CLAUSES.contains(&w) => 1,
+        Some("GROUP") | Some("ORDER") | Some("PARTITION")
+            if words.get(1).is_some_and(|w| w == "BY") =>
+        {
+            2
+        }
+        Some(w) if JOIN_MODIFIERS.contains(&w) => {
+            let modifiers = words
+                .iter()
+                .take_while(|w| JOIN_MODIFIERS.contains(&&w[..]))
+                .count();
+            if words.get(modifiers).is_some_and(|w| w == "JOIN") {
+                modifiers + 1
+            } else {
+                0
+            }
+        }
+        _ => 0,
+    }
+}
@@ -0,0 +1,197 @@
+//! Formatting for stack traces: Java exceptions, Python tracebacks and Rust backtraces, one frame
+//! per line.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::R;
+
+lazy_static! {
+    /// `at com.foo.Bar.baz(Bar.java:12)`, possibly followed by logback's `~[app.jar:1.0]`. Logs
+    /// which have lost their line breaks can have several of these to a line.
+    static ref JAVA_FRAME: Regex =
+        Regex::new(r"\bat\s+([\w$.<>/-]+)\s*(\([^()]*\))(?:\s*(~?\[[^\]]*\]))?").unwrap();
+    /// `File "app.py", line 12, in handler`
+    static ref PYTHON_FRAME: Regex =
+        Regex::new(r#"^(\s*)(File "[^"]*", line \d+,?)\s*(in \S.*)?$"#).unwrap();
+    /// `  12: tokio::runtime::park`, with its location either on the same line or the next.
+    static ref RUST_FRAME: Regex = Regex::new(r"^\s*(\d+:)\s+(\S.*?)(?:\s+(at \S+:\d+(?::\d+)?))?$")
+        .unwrap();
+    static ref RUST_LOCATION: Regex = Regex::new(r"^\s*(at \S+:\d+(?::\d+)?)$").unwrap();
+}
+
+/// Frames are padded so that their locations line up, unless that would take them past this
+/// column.
+const ALIGN_LIMIT: usize = 60;
+
+#[derive(Debug)]
+pub(crate) enum Line {
+    Text(String),
+    /// A frame, split into the part before the aligned column and the part after it.
+    Frame {
+        head: String,
+        tail: Option<String>,
+    },
+    /// Source code quoted underneath a Python frame.
+    Source(String),
+    Omitted(usize),
+}
+
+pub(crate) fn parse(data: &str) -> Vec<Line> {
+    let mut lines = vec![];
+    // The indentation of the last Python frame, since the source lines under it are indented
+    // further.
+    let mut python_indent = None;
+    for line in data.lines() {
+        if line.trim().is_empty() {
+            python_indent = None;
+            continue;
+        }
+        let indent = line.len() - line.trim_start().len();
+        if python_indent.is_some_and(|i| indent > i) {
+            lines.push(Line::Source(line.trim().to_string()));
+            continue;
+        }
+        python_indent = None;
+
+        if let Some(c) = PYTHON_FRAME.captures(line) {
+            python_indent = Some(c[1].len());
+            // The location already leads the frame, so there's nothing to align.
+            let head = match c.get(3) {
+                Some(function) => format!("{} {}", &c[2], function.as_str().trim_end()),
+                None => c[2].to_string(),
+            };
+            lines.push(Line::Frame { head, tail: None });
+        } else if let Some(c) = RUST_FRAME.captures(line) {
+            lines.push(Line::Frame {
+                head: format!("{} {}", &c[1], &c[2]),
+                tail: c.get(3).map(|m| m.as_str().to_string()),
+            });
+        } else if let Some(c) = RUST_LOCATION.captures(line) {
+            match lines.last_mut() {
+                Some(Line::Frame {
+                    tail: tail @ None, ..
+                }) => *tail = Some(c[1].to_string()),
+                _ => lines.push(Line::Text(line.trim().to_string())),
+            }
+        } else {
+            let mut rest = 0;
+            for c in JAVA_FRAME.captures_iter(line) {
+                let m = c.get(0).unwrap();
+                push_text(&mut lines, &line[rest..m.start()]);
+                // The location stays attached to the method, since that's what IDEs look for
+                // when linking frames; only logback's packaging suffix gets aligned.
+                lines.push(Line::Frame {
+                    head: format!("at {}{}", &c[1], &c[2]),
+                    tail: c.get(3).map(|m| m.as_str().to_string()),
+                });
+                rest = m.end();
+            }
+            push_text(&mut lines, &line[rest..]);
+        }
+    }
+    lines
+}
+
+fn push_text(lines: &mut Vec<Line>, text: &str) {
+    let text = text.trim();
+    if !text.is_empty() {
+        lines.push(Line::Text(text.to_string()));
+    }
+}
+
+/// Replaces each run of at least two frames which contain one of `patterns` with a single
+/// [`Line::Omitted`].
+pub(crate) fn collapse(lines: Vec<Line>, patterns: &[String]) -> Vec<Line> {
+    if patterns.is_empty() {
+        return lines;
+    }
+    let mut out = vec![];
+    // The run of framework frames (and their source lines) seen so far, and how many frames it
+    // has.
+    let mut run = vec![];
+    let mut frames = 0;
+    for line in lines {
+        let is_framework = match &line {
+            Line::Frame { head, tail } => patterns.iter().any(|p| {
+                head.contains(p.as_str()) || tail.as_ref().is_some_and(|t| t.contains(p.as_str()))
+            }),
+            Line::Source(_) => {
+                if !run.is_empty() {
+                    run.push(line);
+                } else {
+                    out.push(line);
+                }
+                continue;
+            }
+            _ => false,
+        };
+        if is_framework {
+            run.push(line);
+            frames += 1;
+            continue;
+        }
+        flush_run(&mut out, &mut run, &mut frames);
+        out.push(line);
+    }
+    flush_run(&mut out, &mut run, &mut frames);
+    out
+}
+
+fn flush_run(out: &mut Vec<Line>, run: &mut Vec<Line>, frames: &mut usize) {
+    if *frames >= 2 {
+        out.push(Line::Omitted(*frames));
+        run.clear();
+    } else {
+        out.append(run);
+    }
+    *frames = 0;
+}
+
+pub(crate) fn format(lines: &[Line], out: &mut Vec<R>) {
+    let mut width = 0;
+    for (idx, line) in lines.iter().enumerate() {
+        if idx != 0 {
+            out.push(R::Newline);
+        }
+        match line {
+            // Java's `... 12 more`, which stands in for frames.
+            Line::Text(text) if text.starts_with("...") => {
+                out.push(R::String(format!("  {}", text)));
+            }
+            Line::Text(text) => {
+                out.push(R::String(text.clone()));
+                width = 0;
+            }
+            Line::Frame { head, tail } => {
+                if width == 0 {
+                    width = align_width(&lines[idx..]);
+                }
+                let mut s = format!("  {}", head);
+                if let Some(tail) = tail {
+                    s.push_str(&" ".repeat(width.saturating_sub(head.len()) + 1));
+                    s.push_str(tail);
+                }
+                out.push(R::String(s));
+            }
+            Line::Source(source) => out.push(R::String(format!("    {}", source))),
+            Line::Omitted(n) => out.push(R::String(format!("  ... {} frames omitted", n))),
+        }
+    }
+}
+
+/// The column which the locations of the frames at the start of `lines` are aligned to.
+fn align_width(lines: &[Line]) -> usize {
+    lines
+        .iter()
+        .take_while(|line| !matches!(line, Line::Text(_)))
+        .filter_map(|line| match line {
+            Line::Frame {
+                head,
+                tail: Some(_),
+            } if head.len() <= ALIGN_LIMIT => Some(head.len()),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
@@ -0,0 +1,381 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::{blob, markup, Dialect, Options};
+
+lazy_static! {
+    /// Tokens which contain characters that the grammar treats as structure (`:`, `,`, `[`, ...)
+    /// but which must be copied through untouched: URLs, `file:line:col` locations, IP addresses
+    /// with ports, UUIDs and email addresses.
+    static ref ATOM: Regex = Regex::new(concat!(
+        // scheme://authority/path?query#fragment, which `url_len` trims back to the URL itself.
+        r#"[A-Za-z][A-Za-z0-9+.-]*://[^\s<>{}'"]+"#,
+        // [IPv6]:port
+        r"|\[[0-9A-Fa-f.]*:[0-9A-Fa-f.]*:[0-9A-Fa-f:.]*\](?::\d{1,5})?",
+        // IPv4:port
+        r"|\b\d{1,3}(?:\.\d{1,3}){3}(?::\d{1,5})?\b",
+        // UUID
+        r"|\b[0-9A-Fa-f]{8}(?:-[0-9A-Fa-f]{4}){3}-[0-9A-Fa-f]{12}\b",
+        // path/to/file.ext:line:col, C:\path\to\file.ext:line
+        r"|(?:\b[A-Za-z]:[/\\])?[\w.~@+-]*(?:[/\\][\w.~@+-]+)*\.[A-Za-z][A-Za-z0-9]*:\d+(?::\d+)?\b",
+        // user@example.com
+        r"|[\w.+-]+@[\w-]+(?:\.[\w-]+)+",
+        // Bare IPv6, either uncompressed or with a `::`.
+        r"|\b(?:[0-9A-Fa-f]{1,4}:){7}[0-9A-Fa-f]{1,4}\b",
+        r"|(?:\b[0-9A-Fa-f]{1,4}(?::[0-9A-Fa-f]{1,4})*)?::(?:[0-9A-Fa-f]{1,4}(?::[0-9A-Fa-f]{1,4})*\b)?",
+    ))
+    .unwrap();
+    /// A bare token which might be a hex or base64 blob.
+    static ref BARE_BLOB: Regex = Regex::new(r"^[A-Za-z0-9+/]+={0,2}").unwrap();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SpanKind {
+    /// A token such as a URL or a single-quoted SQL string.
+    Atom,
+    LineComment,
+    BlockComment,
+    /// A triple-quoted or raw string literal, which may span several lines.
+    RawString,
+    /// A double-quoted string literal containing escaped newlines.
+    String,
+    /// A double-quoted string literal which holds an escaped container, as in
+    /// `"{\"a\": [1, 2]}"`. Only picked out with `unwrap_encoded_strings`.
+    EncodedString,
+    /// Binary data, either a byte string literal or a hex or base64 string or token.
+    Blob,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Span {
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    pub(crate) kind: SpanKind,
+}
+
+/// Byte ranges of the input which are emitted exactly as they appear.
+#[derive(Debug, Default)]
+pub(crate) struct VerbatimSpans {
+    spans: Vec<Span>,
+    /// The positions of the `;`s which aren't in a span or a string literal, and so might
+    /// separate statements.
+    semicolons: Vec<usize>,
+}
+
+impl VerbatimSpans {
+    pub(crate) fn find(data: &str, options: &Options) -> Self {
+        let dialect = options.dialect;
+        let mut spans = vec![];
+        let mut semicolons = vec![];
+        let mut atom = ATOM.find_at(data, 0);
+        let mut elements = markup::element_spans(data).into_iter().peekable();
+        // The end of the plain string literal being scanned, if any. Atoms are still picked out
+        // of those, but comments can't start inside them.
+        let mut quoted_until = 0;
+        let mut prev = None;
+        let mut pos = 0;
+        while let Some(c) = data[pos..].chars().next() {
+            if atom.is_some_and(|m| m.start() < pos) {
+                atom = ATOM.find_at(data, pos);
+            }
+            while elements.next_if(|&(start, _)| start < pos).is_some() {}
+            let span = if pos < quoted_until {
+                None
+            } else if let Some((_, end)) = elements.next_if(|&(start, _)| start == pos) {
+                // Markup inside other text is copied through rather than taken apart.
+                Some(Span {
+                    start: pos,
+                    end,
+                    kind: SpanKind::Atom,
+                })
+            } else if let Some((end, kind)) = find_string(data, pos, prev, options) {
+                if kind.is_none() {
+                    quoted_until = end;
+                }
+                kind.map(|kind| Span {
+                    start: pos,
+                    end,
+                    kind,
+                })
+            } else if prev.is_none_or(char::is_whitespace) {
+                // Comment markers only count at the start of a line or after whitespace, so that
+                // things like `a//b` or `foo#bar` are left alone.
+                find_comment(data, pos, dialect)
+            } else {
+                None
+            };
+            let span = span.or_else(|| {
+                atom.filter(|m| m.start() == pos && is_atom(m.as_str()))
+                    .map(|m| Span {
+                        start: pos,
+                        end: pos + url_len(m.as_str()),
+                        kind: SpanKind::Atom,
+                    })
+                    .filter(|span| span.end > span.start)
+            });
+            let span = span.or_else(|| find_bare_blob(data, pos, prev));
+
+            if let Some(span) = span {
+                spans.push(span);
+                prev = data[..span.end].chars().next_back();
+                pos = span.end;
+            } else {
+                if c == ';' && pos >= quoted_until {
+                    semicolons.push(pos);
+                }
+                prev = Some(c);
+                pos += c.len_utf8();
+            }
+        }
+        VerbatimSpans { spans, semicolons }
+    }
+
+    /// Replaces every character which the grammar treats as structure with a placeholder, so
+    /// that each span parses as part of a single `text` node. Byte offsets are unchanged.
+    pub(crate) fn mask(&self, data: &str) -> String {
+        let mut bytes = data.as_bytes().to_vec();
+        for span in &self.spans {
+            for b in &mut bytes[span.start..span.end] {
+                if b"()[]{},:=<>".contains(b) {
+                    *b = b'_';
+                }
+            }
+        }
+        // Only ASCII bytes were replaced, so this is still valid UTF-8.
+        String::from_utf8(bytes).unwrap()
+    }
+
+    /// The spans which overlap `start..end`, in order.
+    pub(crate) fn overlapping(&self, start: usize, end: usize) -> impl Iterator<Item = &Span> {
+        let first = self.spans.partition_point(|s| s.end <= start);
+        self.spans[first..]
+            .iter()
+            .take_while(move |s| s.start < end)
+    }
+
+    /// The positions of the `;`s in `start..end` which might separate statements, in order.
+    pub(crate) fn semicolons(&self, start: usize, end: usize) -> impl Iterator<Item = usize> + '_ {
+        let first = self.semicolons.partition_point(|&pos| pos < start);
+        self.semicolons[first..]
+            .iter()
+            .copied()
+            .take_while(move |&pos| pos < end)
+    }
+}
+
+/// Matches a comment starting at `pos`.
+fn find_comment(data: &str, pos: usize, dialect: Dialect) -> Option<Span> {
+    let rest = &data[pos..];
+    if dialect.line_comment().is_some_and(|m| rest.starts_with(m)) {
+        return Some(Span {
+            start: pos,
+            end: rest.find('\n').map_or(data.len(), |e| pos + e),
+            kind: SpanKind::LineComment,
+        });
+    }
+    let (open, close) = dialect.block_comment()?;
+    let body = rest.strip_prefix(open)?;
+    Some(Span {
+        start: pos,
+        end: body
+            .find(close)
+            .map_or(data.len(), |e| pos + open.len() + e + close.len()),
+        kind: SpanKind::BlockComment,
+    })
+}
+
+/// Matches a string literal starting at `pos`, returning its end and, if it should be kept
+/// verbatim, its kind.
+fn find_string(
+    data: &str,
+    pos: usize,
+    prev: Option<char>,
+    options: &Options,
+) -> Option<(usize, Option<SpanKind>)> {
+    let dialect = options.dialect;
+    let rest = &data[pos..];
+    // SQL strings escape quotes by doubling them, as in 'it''s'.
+    if dialect == Dialect::Sql && rest.starts_with('\'') {
+        let mut chars = rest.char_indices().skip(1).peekable();
+        while let Some((i, c)) = chars.next() {
+            if c == '\'' && chars.next_if(|&(_, c)| c == '\'').is_none() {
+                return Some((pos + i + 1, Some(SpanKind::Atom)));
+            }
+        }
+        return None;
+    }
+
+    for quotes in &["\"\"\"", "'''"] {
+        if let Some(body) = rest.strip_prefix(quotes) {
+            let end = body.find(quotes)?;
+            return Some((
+                pos + quotes.len() + end + quotes.len(),
+                Some(SpanKind::RawString),
+            ));
+        }
+    }
+
+    // r"...", r#"..."#, br##"..."##, but not the end of an identifier like `bar"`.
+    if !prev.is_some_and(|c| c.is_alphanumeric() || c == '_') {
+        if let Some(raw) = rest.strip_prefix("br").or_else(|| rest.strip_prefix('r')) {
+            let hashes = raw.len() - raw.trim_start_matches('#').len();
+            if raw[hashes..].starts_with('"') {
+                let close = format!("\"{}", &raw[..hashes]);
+                let body_start = data.len() - raw.len() + hashes + 1;
+                let end = data[body_start..].find(&close)?;
+                return Some((body_start + end + close.len(), Some(SpanKind::RawString)));
+            }
+        }
+    }
+
+    if !prev.is_some_and(|c| c.is_alphanumeric() || c == '_') && rest.starts_with("b\"") {
+        let end = pos + 1 + quoted_len(&rest[1..])?;
+        return Some((end, Some(SpanKind::Blob)));
+    }
+
+    if !rest.starts_with('"') {
+        return None;
+    }
+    let mut has_newline = false;
+    let mut chars = rest.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                let kind = if options.unwrap_encoded_strings && is_encoded(&rest[1..i]) {
+                    Some(SpanKind::EncodedString)
+                } else if blob::is_blob(&rest[..i + 1]) {
+                    Some(SpanKind::Blob)
+                } else if has_newline {
+                    Some(SpanKind::String)
+                } else {
+                    None
+                };
+                return Some((pos + i + 1, kind));
+            }
+            '\\' => has_newline |= chars.next().is_some_and(|(_, c)| c == 'n'),
+            '\n' => return None,
+            _ => (),
+        }
+    }
+    None
+}
+
+/// The length of the double-quoted string at the start of `s`, including its quotes, as long as
+/// it doesn't span several lines.
+pub(crate) fn quoted_len(s: &str) -> Option<usize> {
+    let mut chars = s.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some(i + 1),
+            '\\' => {
+                chars.next();
+            }
+            '\n' => return None,
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Matches a hex or base64 token starting at `pos`, as long as it's a whole word.
+fn find_bare_blob(data: &str, pos: usize, prev: Option<char>) -> Option<Span> {
+    if prev.is_some_and(|c| c.is_alphanumeric() || "_+/.-".contains(c)) {
+        return None;
+    }
+    let m = BARE_BLOB.find(&data[pos..])?;
+    let end = pos + m.end();
+    let next = data[end..].chars().next();
+    if next.is_some_and(|c| c.is_alphanumeric() || "_.-".contains(c)) || !blob::is_blob(m.as_str())
+    {
+        return None;
+    }
+    Some(Span {
+        start: pos,
+        end,
+        kind: SpanKind::Blob,
+    })
+}
+
+/// Whether the body of a string literal holds a container once it's unescaped.
+fn is_encoded(body: &str) -> bool {
+    if !body.contains("\\\"") {
+        return false;
+    }
+    let decoded = unescape(body);
+    let decoded = decoded.trim();
+    (decoded.starts_with('{') && decoded.ends_with('}'))
+        || (decoded.starts_with('[') && decoded.ends_with(']'))
+}
+
+/// Resolves the backslash escapes in the body of a string literal. Unknown escapes are kept as
+/// they are.
+pub(crate) fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('0') => out.push('\0'),
+            Some(c @ ('\\' | '"' | '\'' | '/')) => out.push(c),
+            Some('u') => {
+                let hex = chars.as_str().get(..4).unwrap_or("");
+                match u32::from_str_radix(hex, 16).ok().and_then(char::from_u32) {
+                    Some(c) => {
+                        out.push(c);
+                        chars.nth(3);
+                    }
+                    None => out.push_str("\\u"),
+                }
+            }
+            Some(c) => {
+                out.push('\\');
+                out.push(c);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// Filters out matches which are too ambiguous to be worth keeping intact.
+/// The length of the URL at the start of an atom, or of the whole atom if it isn't a URL. URLs can
+/// contain `,` and balanced brackets, as in `wiki/Rust_(programming_language)`, but a closing
+/// bracket without a partner ends them, as does any punctuation at the very end.
+fn url_len(s: &str) -> usize {
+    let Some(scheme_len) = s.find("://") else {
+        return s.len();
+    };
+    let mut open = vec![];
+    let mut end = s.len();
+    for (idx, c) in s.char_indices().skip(scheme_len + 3) {
+        match c {
+            '(' | '[' => open.push(c),
+            ')' | ']' if open.pop() != Some(if c == ')' { '(' } else { '[' }) => {
+                end = idx;
+                break;
+            }
+            _ => (),
+        }
+    }
+    let url = s[..end].trim_end_matches(['.', ',', ':', ';', '!', '?']);
+    if url.len() <= scheme_len + 3 {
+        0
+    } else {
+        url.len()
+    }
+}
+
+fn is_atom(s: &str) -> bool {
+    if s.chars().all(|c| c == ':' || c.is_ascii_hexdigit()) {
+        // Compressed IPv6 addresses look a lot like `A::B` paths, so require at least one digit.
+        s.chars().any(|c| c.is_ascii_digit())
+    } else {
+        true
+    }
+}